@@ -0,0 +1,247 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+const ID3V2_HEADER_LEN: usize = 10;
+const ID3V1_TAG_LEN: usize = 128;
+const FRAME_HEADER_LEN: usize = 4;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct AudioMetadata {
+    pub duration_ms: u64,
+    pub size_bytes: u64,
+    pub bitrate_kbps: u32,
+    pub sample_rate: u32,
+    pub frame_count: u64,
+    pub checksum: String,
+    pub truncated: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MpegVersion {
+    V1,
+    V2,
+    V25,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Layer {
+    One,
+    Two,
+    Three,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FrameHeader {
+    sample_rate: u32,
+    samples: u32,
+    length: usize,
+}
+
+impl FrameHeader {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < FRAME_HEADER_LEN || bytes[0] != 0xFF || bytes[1] & 0xE0 != 0xE0 {
+            return None;
+        }
+        let version = match (bytes[1] >> 3) & 0b11 {
+            0 => MpegVersion::V25,
+            2 => MpegVersion::V2,
+            3 => MpegVersion::V1,
+            _ => return None,
+        };
+        let layer = match (bytes[1] >> 1) & 0b11 {
+            1 => Layer::Three,
+            2 => Layer::Two,
+            3 => Layer::One,
+            _ => return None,
+        };
+        let bitrate_index = usize::from(bytes[2] >> 4);
+        // 0 is free format and 15 is reserved, neither can be measured
+        if bitrate_index == 0 || bitrate_index == 15 {
+            return None;
+        }
+        let bitrate = u32::from(bitrate_kbps(version, layer, bitrate_index)) * 1000;
+        let sample_rate = match ((bytes[2] >> 2) & 0b11, version) {
+            (0, MpegVersion::V1) => 44_100,
+            (1, MpegVersion::V1) => 48_000,
+            (2, MpegVersion::V1) => 32_000,
+            (0, MpegVersion::V2) => 22_050,
+            (1, MpegVersion::V2) => 24_000,
+            (2, MpegVersion::V2) => 16_000,
+            (0, MpegVersion::V25) => 11_025,
+            (1, MpegVersion::V25) => 12_000,
+            (2, MpegVersion::V25) => 8_000,
+            _ => return None,
+        };
+        let padding = u32::from((bytes[2] >> 1) & 0b1);
+        let (samples, length) = match layer {
+            Layer::One => (384, (12 * bitrate / sample_rate + padding) * 4),
+            Layer::Two => (1152, 144 * bitrate / sample_rate + padding),
+            Layer::Three if version == MpegVersion::V1 => {
+                (1152, 144 * bitrate / sample_rate + padding)
+            }
+            Layer::Three => (576, 72 * bitrate / sample_rate + padding),
+        };
+        Some(Self {
+            sample_rate,
+            samples,
+            length: usize::try_from(length).ok()?,
+        })
+    }
+}
+
+fn bitrate_kbps(version: MpegVersion, layer: Layer, index: usize) -> u16 {
+    const V1_L1: [u16; 15] = [
+        0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+    ];
+    const V1_L2: [u16; 15] = [
+        0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+    ];
+    const V1_L3: [u16; 15] = [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ];
+    const V2_L1: [u16; 15] = [
+        0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+    ];
+    const V2_L2_L3: [u16; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+    let table = match (version, layer) {
+        (MpegVersion::V1, Layer::One) => &V1_L1,
+        (MpegVersion::V1, Layer::Two) => &V1_L2,
+        (MpegVersion::V1, Layer::Three) => &V1_L3,
+        (_, Layer::One) => &V2_L1,
+        (_, _) => &V2_L2_L3,
+    };
+    table[index]
+}
+
+// skips a leading ID3v2 tag, if any
+fn audio_start(data: &[u8]) -> usize {
+    if data.len() < ID3V2_HEADER_LEN || &data[..3] != b"ID3" {
+        return 0;
+    }
+    // tag size is a 28 bit "syncsafe" integer
+    let size = data[6..10]
+        .iter()
+        .fold(0usize, |acc, byte| (acc << 7) | usize::from(byte & 0x7F));
+    let footer = if data[5] & 0x10 == 0 {
+        0
+    } else {
+        ID3V2_HEADER_LEN
+    };
+    (ID3V2_HEADER_LEN + size + footer).min(data.len())
+}
+
+// excludes a trailing ID3v1 tag, if any
+fn audio_end(data: &[u8]) -> usize {
+    if data.len() >= ID3V1_TAG_LEN && &data[data.len() - ID3V1_TAG_LEN..][..3] == b"TAG" {
+        return data.len() - ID3V1_TAG_LEN;
+    }
+    data.len()
+}
+
+impl AudioMetadata {
+    pub fn from_mp3(data: &[u8]) -> Result<Self> {
+        let start = audio_start(data);
+        let end = audio_end(data).max(start);
+        let audio = &data[start..end];
+        let mut position = 0;
+        let mut frame_count = 0u64;
+        let mut total_samples = 0u64;
+        let mut frame_bytes = 0u64;
+        let mut sample_rate = None;
+        let mut truncated = false;
+        while position + FRAME_HEADER_LEN <= audio.len() {
+            let Some(header) = FrameHeader::parse(&audio[position..]) else {
+                // not on a frame boundary, resync on the next byte
+                position += 1;
+                continue;
+            };
+            if position + header.length > audio.len() {
+                truncated = true;
+                break;
+            }
+            sample_rate.get_or_insert(header.sample_rate);
+            frame_count += 1;
+            total_samples += u64::from(header.samples);
+            frame_bytes += header.length as u64;
+            position += header.length;
+        }
+        let Some(sample_rate) = sample_rate else {
+            anyhow::bail!("no mpeg audio frames found");
+        };
+        let duration_ms = total_samples * 1000 / u64::from(sample_rate);
+        let bitrate_kbps = u32::try_from((frame_bytes * 8).checked_div(duration_ms).unwrap_or(0))?;
+        Ok(Self {
+            duration_ms,
+            size_bytes: data.len() as u64,
+            bitrate_kbps,
+            sample_rate,
+            frame_count,
+            checksum: hex::encode(Sha256::digest(data)),
+            truncated,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // mpeg 1 layer 3 at 128 kbps and 44.1 khz, 417 bytes and 1152 samples a frame
+    const FRAME: [u8; 417] = {
+        let mut frame = [0; 417];
+        frame[0] = 0xFF;
+        frame[1] = 0xFB;
+        frame[2] = 0x90;
+        frame
+    };
+
+    #[test]
+    fn measures_every_frame() {
+        let data = FRAME.repeat(10);
+        let metadata = AudioMetadata::from_mp3(&data).unwrap();
+        assert_eq!(
+            metadata,
+            AudioMetadata {
+                duration_ms: 11_520 * 1000 / 44_100,
+                size_bytes: 4170,
+                bitrate_kbps: 127,
+                sample_rate: 44_100,
+                frame_count: 10,
+                checksum: hex::encode(Sha256::digest(&data)),
+                truncated: false,
+            }
+        );
+    }
+
+    #[test]
+    fn ignores_id3_tags_and_junk_between_frames() {
+        // a 20 byte id3v2 tag holding what would otherwise read as a frame header
+        let mut data = b"ID3\x04\x00\x00\x00\x00\x00\x14\xFF\xFB\x10\x00".to_vec();
+        data.resize(30, 0);
+        data.extend(FRAME.repeat(2));
+        data.extend([0x00, 0x12, 0x34]);
+        data.extend(FRAME);
+        let mut id3v1 = b"TAG".to_vec();
+        id3v1.resize(128, 0xFF);
+        data.extend(id3v1);
+        let metadata = AudioMetadata::from_mp3(&data).unwrap();
+        assert_eq!(metadata.frame_count, 3);
+        assert_eq!(metadata.size_bytes, data.len() as u64);
+        assert!(!metadata.truncated);
+    }
+
+    #[test]
+    fn flags_a_cut_off_last_frame() {
+        let data = FRAME.repeat(3);
+        let metadata = AudioMetadata::from_mp3(&data[..1000]).unwrap();
+        assert_eq!(metadata.frame_count, 2);
+        assert!(metadata.truncated);
+    }
+
+    #[test]
+    fn rejects_data_without_frames() {
+        assert!(AudioMetadata::from_mp3(b"not audio").is_err());
+        assert!(AudioMetadata::from_mp3(&[]).is_err());
+    }
+}
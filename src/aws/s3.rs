@@ -3,7 +3,7 @@ use aws_sdk_s3 as s3;
 use s3::{
     operation::{
         delete_object::DeleteObjectOutput, get_object::GetObjectOutput,
-        list_buckets::ListBucketsOutput, put_object::PutObjectOutput,
+        head_object::HeadObjectError, list_buckets::ListBucketsOutput, put_object::PutObjectOutput,
     },
     presigning::PresigningConfig,
     Client as AwsClient,
@@ -78,4 +78,24 @@ impl Client {
         let output = builder.send().await?;
         Ok(output)
     }
+
+    // copies then deletes the original, false when there was nothing at `from` to move
+    pub async fn move_object(&self, from: &str, to: &str) -> Result<bool> {
+        let Self { bucket, client } = self;
+        if let Err(err) = client.head_object().bucket(bucket).key(from).send().await {
+            return match err.into_service_error() {
+                HeadObjectError::NotFound(_) => Ok(false),
+                err => Err(err.into()),
+            };
+        }
+        client
+            .copy_object()
+            .bucket(bucket)
+            .copy_source(format!("{bucket}/{from}"))
+            .key(to)
+            .send()
+            .await?;
+        self.delete_object(from).await?;
+        Ok(true)
+    }
 }
@@ -1,7 +1,23 @@
+use std::{collections::HashMap, time::Duration};
+
 use anyhow::Result;
 use aws_sdk_sqs as sqs;
 use serde::{Deserialize, Serialize};
-use sqs::{operation::send_message::SendMessageOutput, Client as AwsClient};
+use sqs::{
+    operation::send_message::SendMessageOutput,
+    types::{
+        ChangeMessageVisibilityBatchRequestEntry, Message, MessageSystemAttributeName,
+        QueueAttributeName, SendMessageBatchRequestEntry,
+    },
+    Client as AwsClient,
+};
+
+// sqs never handles more than this in one receive or batch
+const MAX_BATCH_MESSAGES: usize = 10;
+// the longest long poll sqs allows
+const MAX_WAIT_TIME: Duration = Duration::from_secs(20);
+// long enough to list a queue before anything reappears
+const PEEK_VISIBILITY: Duration = Duration::from_secs(30);
 
 pub struct FifoQueue {
     pub queue_url: String,
@@ -14,6 +30,75 @@ pub struct FifoMessage<T: Serialize + for<'a> Deserialize<'a>> {
     pub deduplication_id: String,
 }
 
+pub struct ReceiveOptions {
+    pub max_messages: usize,
+    // long polling, zero returns straight away
+    pub wait_time: Duration,
+    // the queue's own default when not set
+    pub visibility_timeout: Option<Duration>,
+}
+
+impl Default for ReceiveOptions {
+    fn default() -> Self {
+        Self {
+            max_messages: MAX_BATCH_MESSAGES,
+            wait_time: MAX_WAIT_TIME,
+            visibility_timeout: None,
+        }
+    }
+}
+
+// a message as received, the receipt handle is what acks or extends it
+#[derive(Debug, Clone, Serialize)]
+pub struct ReceivedMessage {
+    pub message_id: String,
+    #[serde(skip)]
+    pub receipt_handle: String,
+    pub body: String,
+    pub group: Option<String>,
+    pub deduplication_id: Option<String>,
+    pub receive_count: u32,
+    // epoch millis of the original send
+    pub sent_at: Option<i64>,
+    pub attributes: HashMap<String, String>,
+}
+
+impl ReceivedMessage {
+    fn from_message(message: Message) -> Option<Self> {
+        let attributes = message
+            .attributes()
+            .map(|attributes| {
+                attributes
+                    .iter()
+                    .map(|(name, value)| (name.as_str().to_string(), value.to_string()))
+                    .collect::<HashMap<_, _>>()
+            })
+            .unwrap_or_default();
+        let attribute = |name: MessageSystemAttributeName| attributes.get(name.as_str()).cloned();
+        Some(Self {
+            group: attribute(MessageSystemAttributeName::MessageGroupId),
+            deduplication_id: attribute(MessageSystemAttributeName::MessageDeduplicationId),
+            receive_count: attribute(MessageSystemAttributeName::ApproximateReceiveCount)
+                .and_then(|count| count.parse().ok())
+                .unwrap_or_default(),
+            sent_at: attribute(MessageSystemAttributeName::SentTimestamp)
+                .and_then(|sent_at| sent_at.parse().ok()),
+            message_id: message.message_id()?.to_string(),
+            receipt_handle: message.receipt_handle()?.to_string(),
+            body: message.body().unwrap_or_default().to_string(),
+            attributes,
+        })
+    }
+
+    pub fn parse<T: for<'a> Deserialize<'a>>(&self) -> serde_json::Result<T> {
+        serde_json::from_str::<T>(&self.body)
+    }
+}
+
+fn seconds(duration: Duration) -> Result<i32> {
+    Ok(i32::try_from(duration.as_secs())?)
+}
+
 impl FifoQueue {
     pub async fn new(queue_url: String) -> Self {
         let config = aws_config::load_from_env().await;
@@ -25,29 +110,242 @@ impl FifoQueue {
         &self,
         message: FifoMessage<T>,
     ) -> Result<SendMessageOutput> {
-        let Self { queue_url, client } = self;
         let body = serde_json::to_string(&message.body)?;
+        self.send_raw_fifo_message(&body, &message.group, &message.deduplication_id)
+            .await
+    }
+
+    // for forwarding bodies as received, which may not even parse
+    pub async fn send_raw_fifo_message(
+        &self,
+        body: &str,
+        group: &str,
+        deduplication_id: &str,
+    ) -> Result<SendMessageOutput> {
+        let Self { queue_url, client } = self;
         let response = client
             .send_message()
             .queue_url(queue_url)
-            .message_body(&body)
-            .message_group_id(&message.group)
-            .message_deduplication_id(&message.deduplication_id)
+            .message_body(body)
+            .message_group_id(group)
+            .message_deduplication_id(deduplication_id)
             .send()
             .await?;
         Ok(response)
     }
 
-    pub async fn receive_fifo_message<T: for<'a> Deserialize<'a>>(&self) -> Result<Vec<T>> {
+    // sent ten at a time, on failure the whole batch can be sent again since the dedup ids hold
+    pub async fn send_message_batch<T: Serialize + for<'a> Deserialize<'a>>(
+        &self,
+        messages: Vec<FifoMessage<T>>,
+    ) -> Result<()> {
+        let Self { queue_url, client } = self;
+        for chunk in messages.chunks(MAX_BATCH_MESSAGES) {
+            let mut entries = vec![];
+            for (index, message) in chunk.iter().enumerate() {
+                let entry = SendMessageBatchRequestEntry::builder()
+                    .id(index.to_string())
+                    .message_body(serde_json::to_string(&message.body)?)
+                    .message_group_id(&message.group)
+                    .message_deduplication_id(&message.deduplication_id)
+                    .build()?;
+                entries.push(entry);
+            }
+            let output = client
+                .send_message_batch()
+                .queue_url(queue_url)
+                .set_entries(Some(entries))
+                .send()
+                .await?;
+            let failed = output
+                .failed()
+                .iter()
+                .filter_map(|failure| failure.id().parse::<usize>().ok())
+                .filter_map(|index| chunk.get(index))
+                .map(|message| message.deduplication_id.as_str())
+                .collect::<Vec<_>>();
+            if !failed.is_empty() {
+                anyhow::bail!("error sending messages {}", failed.join(", "));
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn receive_messages(&self, options: &ReceiveOptions) -> Result<Vec<ReceivedMessage>> {
         let Self { queue_url, client } = self;
-        let output = client.receive_message().queue_url(queue_url).send().await?;
+        let max_messages = options.max_messages.clamp(1, MAX_BATCH_MESSAGES);
+        let visibility_timeout = match options.visibility_timeout {
+            Some(visibility_timeout) => Some(seconds(visibility_timeout)?),
+            None => None,
+        };
+        let output = client
+            .receive_message()
+            .queue_url(queue_url)
+            .max_number_of_messages(i32::try_from(max_messages)?)
+            .wait_time_seconds(seconds(options.wait_time.min(MAX_WAIT_TIME))?)
+            .set_visibility_timeout(visibility_timeout)
+            .attribute_names(QueueAttributeName::All)
+            .send()
+            .await?;
+        let messages = output
+            .messages
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(ReceivedMessage::from_message)
+            .collect();
+        Ok(messages)
+    }
+
+    // acks the message so it is never received again
+    pub async fn delete_message(&self, message: &ReceivedMessage) -> Result<()> {
+        let Self { queue_url, client } = self;
+        client
+            .delete_message()
+            .queue_url(queue_url)
+            .receipt_handle(&message.receipt_handle)
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    // keeps long jobs hidden from other consumers, zero hands the message straight back
+    pub async fn change_visibility(
+        &self,
+        receipt_handle: &str,
+        visibility_timeout: Duration,
+    ) -> Result<()> {
+        let Self { queue_url, client } = self;
+        client
+            .change_message_visibility()
+            .queue_url(queue_url)
+            .receipt_handle(receipt_handle)
+            .visibility_timeout(seconds(visibility_timeout)?)
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    // ten at a time rather than a call per message
+    pub async fn change_visibility_batch(
+        &self,
+        messages: &[ReceivedMessage],
+        visibility_timeout: Duration,
+    ) -> Result<()> {
+        let Self { queue_url, client } = self;
+        // a failed batch does not stop the rest from being changed
+        let mut failed = vec![];
+        for chunk in messages.chunks(MAX_BATCH_MESSAGES) {
+            let mut entries = vec![];
+            for (index, message) in chunk.iter().enumerate() {
+                let entry = ChangeMessageVisibilityBatchRequestEntry::builder()
+                    .id(index.to_string())
+                    .receipt_handle(&message.receipt_handle)
+                    .visibility_timeout(seconds(visibility_timeout)?)
+                    .build()?;
+                entries.push(entry);
+            }
+            let output = client
+                .change_message_visibility_batch()
+                .queue_url(queue_url)
+                .set_entries(Some(entries))
+                .send()
+                .await;
+            match output {
+                Ok(output) => failed.extend(
+                    output
+                        .failed()
+                        .iter()
+                        .filter_map(|failure| failure.id().parse::<usize>().ok())
+                        .filter_map(|index| chunk.get(index))
+                        .map(|message| message.message_id.as_str()),
+                ),
+                Err(err) => {
+                    tracing::error!("error changing message visibility: {err:?}");
+                    failed.extend(chunk.iter().map(|message| message.message_id.as_str()));
+                }
+            }
+        }
+        if !failed.is_empty() {
+            anyhow::bail!("error changing the visibility of {}", failed.join(", "));
+        }
+        Ok(())
+    }
+}
+
+pub struct DeadLetterQueue {
+    pub queue: FifoQueue,
+    // where redriven messages are sent back to
+    pub source: FifoQueue,
+}
+
+impl DeadLetterQueue {
+    pub async fn new(queue_url: String, source_queue_url: String) -> Self {
+        Self {
+            queue: FifoQueue::new(queue_url).await,
+            source: FifoQueue::new(source_queue_url).await,
+        }
+    }
+
+    // received messages stay hidden for `visibility_timeout` unless released
+    pub async fn receive(
+        &self,
+        limit: usize,
+        visibility_timeout: Duration,
+    ) -> Result<Vec<ReceivedMessage>> {
         let mut results = vec![];
-        for message in output.messages.unwrap_or_default() {
-            if let Some(body) = message.body() {
-                let data = serde_json::from_str::<T>(body)?;
-                results.push(data);
+        while results.len() < limit {
+            let options = ReceiveOptions {
+                max_messages: limit - results.len(),
+                wait_time: Duration::ZERO,
+                visibility_timeout: Some(visibility_timeout),
+            };
+            let messages = match self.queue.receive_messages(&options).await {
+                Ok(messages) => messages,
+                Err(err) => {
+                    // nothing is returned, so nothing received so far may stay hidden
+                    if let Err(release_err) = self.release(&results).await {
+                        tracing::error!("error releasing dead letters: {release_err:?}");
+                    }
+                    return Err(err);
+                }
+            };
+            if messages.is_empty() {
+                break;
             }
+            results.extend(messages);
         }
         Ok(results)
     }
+
+    // lists without consuming, everything received is released again
+    pub async fn peek(&self, limit: usize) -> Result<Vec<ReceivedMessage>> {
+        let dead_letters = self.receive(limit, PEEK_VISIBILITY).await?;
+        // they are visible again once the peek visibility runs out
+        if let Err(err) = self.release(&dead_letters).await {
+            tracing::error!("error releasing peeked dead letters: {err:?}");
+        }
+        Ok(dead_letters)
+    }
+
+    pub async fn release(&self, dead_letters: &[ReceivedMessage]) -> Result<()> {
+        self.queue
+            .change_visibility_batch(dead_letters, Duration::ZERO)
+            .await
+    }
+
+    pub async fn delete(&self, dead_letter: &ReceivedMessage) -> Result<()> {
+        self.queue.delete_message(dead_letter).await
+    }
+
+    // the dead letter's own message id dedupes, the original id may still be in the dedup window
+    pub async fn redrive(&self, dead_letter: &ReceivedMessage, body: &str) -> Result<()> {
+        let group = dead_letter
+            .group
+            .as_deref()
+            .unwrap_or(&dead_letter.message_id);
+        self.source
+            .send_raw_fifo_message(body, group, &dead_letter.message_id)
+            .await?;
+        self.delete(dead_letter).await
+    }
 }
@@ -1,12 +1,42 @@
 use anyhow::Result;
 use lambda_web::{
-    actix_web::{web::scope, App},
+    actix_web::{
+        dev::Service,
+        web::{self, scope},
+        App,
+    },
     run_actix_on_lambda as run,
 };
-use parrot_api::{controllers::routes, logger};
+use parrot_api::{
+    controllers::routes,
+    eleven_labs::{ElevenLabs, ElevenLabsConfig},
+    env::{Config, Section},
+    errors,
+    helpers::RequestId,
+    logger,
+    secrets::SecretStore,
+};
 
 #[tokio::main]
 pub async fn main() -> Result<(), lambda_http::Error> {
-    logger::init()?;
-    run(move || App::new().service(scope("/api").configure(routes))).await
+    let config = Config::load(&[Section::ElevenLabs, Section::Queues, Section::Buckets])?;
+    logger::init(&config)?;
+    let secrets = SecretStore::load(&config.secrets)?;
+    let eleven_labs = ElevenLabsConfig::new(config.eleven_labs()?, secrets.clone());
+    let eleven_labs = web::Data::new(ElevenLabs::new(&eleven_labs)?);
+    let config = web::Data::new(config);
+    let secrets = web::Data::from(secrets);
+    run(move || {
+        App::new()
+            .app_data(config.clone())
+            .app_data(secrets.clone())
+            .app_data(eleven_labs.clone())
+            .wrap_fn(|req, srv| {
+                let request_id = RequestId::of(req.request());
+                let res = srv.call(req);
+                async move { Ok(errors::with_request_id(res.await?, &request_id.0)) }
+            })
+            .service(scope("/api").configure(routes))
+    })
+    .await
 }
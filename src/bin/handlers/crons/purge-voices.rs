@@ -0,0 +1,80 @@
+use anyhow::Result;
+use aws_lambda_events::event::cloudwatch_events::CloudWatchEvent;
+use lambda_runtime::{run, service_fn, LambdaEvent};
+use mongoose::{bson::doc, Model};
+use parrot_api::{
+    aws::s3::Client,
+    env::{Config, Section},
+    logger,
+    models::{
+        audit_event::{AuditAction, AuditEvent},
+        output::Output,
+        voice::Voice,
+    },
+};
+
+async fn purge(
+    voice: &Voice,
+    config: &Config,
+    samples_bucket: &Client,
+    outputs_bucket: &Client,
+) -> Result<()> {
+    samples_bucket.delete_object(&voice.sample_key()).await?;
+    if config.retention.purge_voice_outputs {
+        let outputs = Output::list(Some(doc! { "voice": &voice.id }), None).await?;
+        for output in &outputs {
+            outputs_bucket.delete_object(&output.object_key()).await?;
+        }
+        let deleted = Output::bulk_delete(doc! { "voice": &voice.id }).await?;
+        tracing::info!(
+            "PURGED {} OUTPUTS FOR VOICE {}",
+            deleted.deleted_count,
+            voice.id
+        );
+    }
+    Voice::delete(doc! { "_id": &voice.id }).await?;
+    AuditEvent::record(
+        &voice.workspace,
+        "worker:purge-voices",
+        AuditAction::VoicePurged,
+        format!("voice:{}", voice.id),
+        None,
+    )
+    .await?;
+    Ok(())
+}
+
+pub async fn handler(_: LambdaEvent<CloudWatchEvent>, config: &Config) -> Result<()> {
+    let buckets = config.buckets()?;
+    let samples_bucket = Client::new(&buckets.samples_bucket_name).await;
+    let outputs_bucket = Client::new(&buckets.outputs_bucket_name).await;
+    let voices = Voice::purgeable().await?;
+    // one voice failing must not hold back the rest, it is retried on the next run
+    let mut failed = vec![];
+    for voice in &voices {
+        match purge(voice, config, &samples_bucket, &outputs_bucket).await {
+            Ok(()) => tracing::info!("PURGED VOICE {:?}", voice),
+            Err(err) => {
+                tracing::error!("error purging voice {}: {err:?}", voice.id);
+                failed.push(voice.id.as_str());
+            }
+        }
+    }
+    if !failed.is_empty() {
+        anyhow::bail!(
+            "failed to purge {} of {} voices: {}",
+            failed.len(),
+            voices.len(),
+            failed.join(", ")
+        );
+    }
+    Ok(())
+}
+
+#[tokio::main]
+pub async fn main() -> Result<(), lambda_http::Error> {
+    let config = Config::load(&[Section::Buckets])?;
+    logger::init(&config)?;
+    let config = &config;
+    run(service_fn(move |event| handler(event, config))).await
+}
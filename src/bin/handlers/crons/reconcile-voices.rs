@@ -0,0 +1,128 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use aws_lambda_events::event::cloudwatch_events::CloudWatchEvent;
+use lambda_runtime::{run, service_fn, LambdaEvent};
+use mongoose::{
+    bson::{doc, DateTime},
+    Model,
+};
+use parrot_api::{
+    eleven_labs::{ElevenLabs, ElevenLabsConfig, ProviderError},
+    env::{Config, Section},
+    logger,
+    models::{
+        audit_event::{AuditAction, AuditEvent},
+        provider_voice::ProviderVoice,
+        voice::{Voice, VoiceStatus},
+    },
+    secrets::SecretStore,
+};
+
+// only cloned voices are created by parrot, premade voices are never orphans
+const CLONED_CATEGORY: &str = "cloned";
+
+pub async fn handler(
+    _: LambdaEvent<CloudWatchEvent>,
+    config: &Config,
+    eleven_labs: &ElevenLabs,
+) -> Result<()> {
+    let provider_voices = eleven_labs.get_voices().await?;
+    let voices = Voice::list(Some(doc! { "eleven_labs_id": { "$ne": null } }), None).await?;
+    let provider_ids = provider_voices
+        .iter()
+        .map(|voice| voice.voice_id.as_str())
+        .collect::<HashSet<_>>();
+    let linked_ids = voices
+        .iter()
+        .filter_map(|voice| voice.eleven_labs_id.as_deref())
+        .collect::<HashSet<_>>();
+    // voices pointing at an eleven labs voice that no longer exists
+    let dangling = voices
+        .iter()
+        .filter(|voice| {
+            voice
+                .eleven_labs_id
+                .as_deref()
+                .is_some_and(|id| !provider_ids.contains(id))
+        })
+        .collect::<Vec<_>>();
+    // cloned eleven labs voices no parrot voice points at
+    let orphans = provider_voices
+        .iter()
+        .filter(|voice| voice.category == CLONED_CATEGORY)
+        .filter(|voice| !linked_ids.contains(voice.voice_id.as_str()))
+        .collect::<Vec<_>>();
+    tracing::info!(
+        "RECONCILED {} VOICES AGAINST {} PROVIDER VOICES: {} DANGLING, {} ORPHANED",
+        voices.len(),
+        provider_voices.len(),
+        dangling.len(),
+        orphans.len()
+    );
+    for voice in &dangling {
+        tracing::warn!("DANGLING VOICE {:?}", voice);
+    }
+    for voice in &orphans {
+        tracing::warn!("ORPHANED PROVIDER VOICE {:?}", voice);
+    }
+    if !config.retention.reconcile_repair {
+        return Ok(());
+    }
+    for voice in dangling {
+        let empty_eleven_labs_id: Option<String> = None;
+        let updated = Voice::update(
+            doc! { "_id": &voice.id },
+            doc! {
+                "status": VoiceStatus::Failed.to_string(),
+                "eleven_labs_id": empty_eleven_labs_id,
+            },
+        )
+        .await?;
+        AuditEvent::record(
+            &updated.workspace,
+            "worker:reconcile-voices",
+            AuditAction::VoiceMarkedFailed,
+            format!("voice:{}", updated.id),
+            None,
+        )
+        .await?;
+        tracing::info!("MARKED VOICE FAILED {:?}", updated);
+    }
+    // only clones parrot recorded itself are deleted, anything else is left for a person to review
+    let orphan_ids = orphans
+        .iter()
+        .map(|voice| voice.voice_id.as_str())
+        .collect::<Vec<_>>();
+    let grace_ms = config.retention.reconcile_grace_mins * 60 * 1000;
+    let cloned_before = DateTime::from_millis(DateTime::now().timestamp_millis() - grace_ms);
+    for provider_voice in ProviderVoice::deletable(&orphan_ids, cloned_before).await? {
+        match eleven_labs.delete_voice(&provider_voice.id).await {
+            Ok(()) | Err(ProviderError::VoiceNotFound { .. }) => (),
+            Err(err) => return Err(err.into()),
+        }
+        AuditEvent::record(
+            &provider_voice.workspace,
+            "worker:reconcile-voices",
+            AuditAction::ProviderVoiceDeleted,
+            format!("provider-voice:{}", provider_voice.id),
+            None,
+        )
+        .await?;
+        tracing::info!("DELETED ORPHANED PROVIDER VOICE {:?}", provider_voice);
+    }
+    Ok(())
+}
+
+#[tokio::main]
+pub async fn main() -> Result<(), lambda_http::Error> {
+    let config = Config::load(&[Section::ElevenLabs])?;
+    logger::init(&config)?;
+    // built once so warm invocations reuse the provider connections
+    let eleven_labs = ElevenLabs::new(&ElevenLabsConfig::new(
+        config.eleven_labs()?,
+        SecretStore::load(&config.secrets)?,
+    ))?;
+    let (config, eleven_labs) = (&config, &eleven_labs);
+    run(service_fn(move |event| handler(event, config, eleven_labs))).await
+}
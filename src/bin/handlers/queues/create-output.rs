@@ -1,48 +1,52 @@
 use anyhow::Result;
-use aws_lambda_events::event::sqs::SqsEvent;
+use aws_lambda_events::event::sqs::{SqsBatchResponse, SqsEvent};
 use lambda_runtime::{run, service_fn, LambdaEvent};
-use mongoose::{bson::doc, Model};
 use parrot_api::{
     aws::s3::Client,
-    eleven_labs::ElevenLabs,
-    env::Config,
+    eleven_labs::{ElevenLabs, ElevenLabsConfig},
+    env::{Config, Section},
     logger,
-    models::output::Output,
-    models::{output::OutputStatus, voice::Voice},
-    types::CreateOutputFifoMessage,
+    secrets::SecretStore,
+    worker::{create_output, Worker},
 };
 
-pub async fn handler(event: LambdaEvent<SqsEvent>) -> Result<()> {
-    let messages = event.payload.records;
-    let config = Config::new()?;
-    let voice_api = ElevenLabs::new()?;
-    let outputs_bucket = Client::new(&config.outputs_bucket_name).await;
-    for message in messages {
-        let body = message.body.unwrap();
-        let data = serde_json::from_str::<CreateOutputFifoMessage>(&body)?;
-        let output = Output::read_by_id(&data.output_id).await?;
-        let voice = Voice::read_by_id(&output.voice).await?;
-        if voice.eleven_labs_id.is_none() {
-            anyhow::bail!("no eleven labs id supplied");
-        };
-        let bytes = voice_api
-            .text_to_speech(&voice.eleven_labs_id.unwrap(), &output.text)
-            .await?;
-        let updated = Output::update(
-            doc! { "_id": output.id },
-            doc! { "status": OutputStatus::Done.to_string() },
-        )
-        .await?;
-        let key = format!("{}.mp3", updated.id);
-        outputs_bucket.put_object(&key, bytes.to_vec()).await?;
-        // TODO: send server side event of process complete
-        tracing::info!("OUTPUT: {:?}", updated);
-    }
-    Ok(())
+pub async fn handler(
+    event: LambdaEvent<SqsEvent>,
+    config: &Config,
+    voice_api: &ElevenLabs,
+) -> Result<SqsBatchResponse> {
+    let outputs_bucket = Client::new(&config.buckets()?.outputs_bucket_name).await;
+    let outputs_bucket = &outputs_bucket;
+    let queues = config.queues()?;
+    let worker = Worker::new(
+        create_output::NAME,
+        config.worker.concurrency,
+        queues.create_output_queue_url.to_string(),
+        queues.create_output_dead_letter_queue_url.clone(),
+    )
+    .await;
+    let response = worker
+        .run(event.payload.records, |message| {
+            create_output::process(
+                message,
+                voice_api,
+                outputs_bucket,
+                config.worker.max_receive_count,
+            )
+        })
+        .await;
+    Ok(response)
 }
 
 #[tokio::main]
 pub async fn main() -> Result<(), lambda_http::Error> {
-    logger::init()?;
-    run(service_fn(handler)).await
+    let config = Config::load(&[Section::ElevenLabs, Section::Queues, Section::Buckets])?;
+    logger::init(&config)?;
+    // built once so warm invocations reuse the provider connections
+    let eleven_labs = ElevenLabs::new(&ElevenLabsConfig::new(
+        config.eleven_labs()?,
+        SecretStore::load(&config.secrets)?,
+    ))?;
+    let (config, eleven_labs) = (&config, &eleven_labs);
+    run(service_fn(move |event| handler(event, config, eleven_labs))).await
 }
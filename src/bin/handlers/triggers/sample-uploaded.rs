@@ -1,40 +1,91 @@
 use anyhow::Result;
 use aws_lambda_events::event::s3::S3Event;
 use lambda_runtime::{run, service_fn, LambdaEvent};
-use mongoose::{bson::doc, Model};
+use mongoose::{
+    bson::{doc, to_bson},
+    Model,
+};
 use parrot_api::{
-    aws::sqs::{FifoMessage, FifoQueue},
-    env::Config,
+    audio::AudioMetadata,
+    aws::{
+        s3::Client,
+        sqs::{FifoMessage, FifoQueue},
+    },
+    env::{Config, Section},
     logger,
-    models::voice::{Voice, VoiceStatus},
-    types::TrainSampleFifoMessage,
+    models::{
+        audit_event::{AuditAction, AuditEvent},
+        consent::Consent,
+        voice::{Voice, VoiceStatus},
+    },
+    types::{Envelope, TrainSampleFifoMessage},
 };
 
-async fn handler(event: LambdaEvent<S3Event>) -> Result<()> {
-    let config = Config::new()?;
-    let sqs = FifoQueue::new(config.train_voice_queue_url).await;
+async fn handler(event: LambdaEvent<S3Event>, config: &Config) -> Result<()> {
+    let sqs = FifoQueue::new(config.queues()?.train_voice_queue_url.to_string()).await;
+    let sample_bucket = Client::new(&config.buckets()?.samples_bucket_name).await;
     for record in event.payload.records {
         let key = match &record.s3.object.key {
             Some(key) => key,
             None => anyhow::bail!("no key exists on object"),
         };
-        let split = key.split(".mp3").collect::<Vec<_>>();
+        if Consent::is_recording_key(key) {
+            tracing::info!("consent recording uploaded: {key}");
+            continue;
+        }
+        // samples are stored as {workspace}/{voice_id}.mp3
+        let file_name = key.rsplit('/').next().unwrap_or(key);
+        let split = file_name.split(".mp3").collect::<Vec<_>>();
         let voice_id = match split.first() {
             Some(str) => *str,
             None => anyhow::bail!("missing file name on split key"),
         };
+        let sample = sample_bucket.get_object(key.to_string()).await?;
+        let data = sample.body.collect().await?.to_vec();
+        let sample_metadata = match AudioMetadata::from_mp3(&data) {
+            Ok(metadata) => Some(metadata),
+            Err(err) => {
+                tracing::warn!("error reading sample {key} audio: {err:?}");
+                None
+            }
+        };
+        if !Consent::is_verified_for(voice_id).await {
+            // training is queued once the consent is verified
+            let updated_voice = Voice::update(
+                doc! { "_id": voice_id },
+                doc! { "sample_metadata": to_bson(&sample_metadata)? },
+            )
+            .await?;
+            tracing::info!("VOICE AWAITING CONSENT {:?}", updated_voice);
+            continue;
+        }
         // push to FIFO for training
-        sqs.send_fifo_message::<TrainSampleFifoMessage>(FifoMessage {
-            body: TrainSampleFifoMessage {
-                voice_id: voice_id.to_string(),
-            },
+        sqs.send_fifo_message(FifoMessage {
+            body: Envelope::new(
+                TrainSampleFifoMessage {
+                    voice_id: voice_id.to_string(),
+                },
+                "worker:sample-uploaded",
+                None,
+            ),
             group: voice_id.to_string(),
             deduplication_id: voice_id.to_string(),
         })
         .await?;
         let updated_voice = Voice::update(
             doc! { "_id": voice_id },
-            doc! { "status": VoiceStatus::Training.to_string(), },
+            doc! {
+                "status": VoiceStatus::Training.to_string(),
+                "sample_metadata": to_bson(&sample_metadata)?,
+            },
+        )
+        .await?;
+        AuditEvent::record(
+            &updated_voice.workspace,
+            "worker:sample-uploaded",
+            AuditAction::VoiceTrainingStarted,
+            format!("voice:{}", updated_voice.id),
+            None,
         )
         .await?;
         tracing::info!("VOICE {:?}", updated_voice);
@@ -44,6 +95,8 @@ async fn handler(event: LambdaEvent<S3Event>) -> Result<()> {
 
 #[tokio::main]
 pub async fn main() -> Result<(), lambda_http::Error> {
-    logger::init()?;
-    run(service_fn(handler)).await
+    let config = Config::load(&[Section::Queues, Section::Buckets])?;
+    logger::init(&config)?;
+    let config = &config;
+    run(service_fn(move |event| handler(event, config))).await
 }
@@ -1,13 +1,26 @@
 use anyhow::Result;
 use parrot_api::{
+    env::Config,
     logger,
-    models::{output::Output, voice::Voice},
+    models::{
+        api_key::ApiKey, audit_event::AuditEvent, consent::Consent, output::Output,
+        usage_counter::UsageCounter, usage_event::UsageEvent, voice::Voice, workspace::Workspace,
+    },
 };
 
 #[tokio::main]
 pub async fn main() -> Result<()> {
-    logger::init()?;
-    let results = futures::try_join!(Voice::migrate(), Output::migrate())?;
+    logger::init(&Config::load(&[])?)?;
+    let results = futures::try_join!(
+        Voice::migrate(),
+        Output::migrate(),
+        ApiKey::migrate(),
+        Workspace::migrate(),
+        UsageCounter::migrate(),
+        UsageEvent::migrate(),
+        AuditEvent::migrate(),
+        Consent::migrate()
+    )?;
     tracing::info!("{:#?}", results);
     Ok(())
 }
@@ -0,0 +1,69 @@
+use anyhow::Result;
+use mongoose::{
+    bson::{doc, Document},
+    Model,
+};
+use parrot_api::{
+    aws::s3::Client,
+    env::{Config, Section},
+    logger,
+    models::{
+        api_key::ApiKey,
+        output::Output,
+        voice::Voice,
+        workspace::{Workspace, DEFAULT_WORKSPACE},
+    },
+};
+
+// objects were keyed by id alone before workspaces existed
+fn legacy_key(id: &str) -> String {
+    format!("{id}.mp3")
+}
+
+// only the ids, the documents do not deserialize without a workspace
+async fn unmigrated_ids<M: Model>(filter: &Document) -> Result<Vec<String>> {
+    let pipeline = vec![doc! { "$match": filter }, doc! { "$project": { "_id": 1 } }];
+    let ids = M::aggregate_raw::<Document>(pipeline)
+        .await?
+        .iter()
+        .filter_map(|document| document.get_str("_id").ok().map(ToString::to_string))
+        .collect();
+    Ok(ids)
+}
+
+// moves documents created before workspaces existed into the default workspace
+#[tokio::main]
+pub async fn main() -> Result<()> {
+    let config = Config::load(&[Section::Buckets])?;
+    logger::init(&config)?;
+    let buckets = config.buckets()?;
+    let workspace = Workspace::ensure_default().await?;
+    let filter = doc! { "workspace": { "$exists": false } };
+    // objects move before the documents, so a failed run can simply be run again
+    let samples_bucket = Client::new(&buckets.samples_bucket_name).await;
+    let mut moved_samples = 0;
+    for id in unmigrated_ids::<Voice>(&filter).await? {
+        let key = format!("{DEFAULT_WORKSPACE}/{id}.mp3");
+        if samples_bucket.move_object(&legacy_key(&id), &key).await? {
+            moved_samples += 1;
+        }
+    }
+    let outputs_bucket = Client::new(&buckets.outputs_bucket_name).await;
+    let mut moved_outputs = 0;
+    for id in unmigrated_ids::<Output>(&filter).await? {
+        let key = format!("{DEFAULT_WORKSPACE}/{id}.mp3");
+        if outputs_bucket.move_object(&legacy_key(&id), &key).await? {
+            moved_outputs += 1;
+        }
+    }
+    tracing::info!("MOVED {moved_samples} SAMPLES AND {moved_outputs} OUTPUTS");
+    let updates = doc! { "workspace": DEFAULT_WORKSPACE };
+    let results = futures::try_join!(
+        Voice::bulk_update(filter.clone(), updates.clone()),
+        Output::bulk_update(filter.clone(), updates.clone()),
+        ApiKey::bulk_update(filter, updates),
+    )?;
+    tracing::info!("{:#?}", workspace);
+    tracing::info!("{:#?}", results);
+    Ok(())
+}
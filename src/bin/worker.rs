@@ -0,0 +1,74 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use parrot_api::{
+    aws::s3::Client,
+    eleven_labs::{ElevenLabs, ElevenLabsConfig},
+    env::{Config, Section},
+    logger,
+    secrets::SecretStore,
+    worker::{create_output, train_sample, Worker},
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+
+// the queue consumers as one long running process, for running outside of lambda
+#[tokio::main]
+pub async fn main() -> Result<()> {
+    let config = Config::load(&[Section::ElevenLabs, Section::Queues, Section::Buckets])?;
+    logger::init(&config)?;
+    let (queues, buckets) = (config.queues()?, config.buckets()?);
+    let eleven_labs = ElevenLabs::new(&ElevenLabsConfig::new(
+        config.eleven_labs()?,
+        SecretStore::load(&config.secrets)?,
+    ))?;
+    let outputs_bucket = Client::new(&buckets.outputs_bucket_name).await;
+    let samples_bucket = Client::new(&buckets.samples_bucket_name).await;
+    let create_output_worker = Worker::new(
+        create_output::NAME,
+        config.worker.concurrency,
+        queues.create_output_queue_url.to_string(),
+        queues.create_output_dead_letter_queue_url.clone(),
+    )
+    .await;
+    let train_sample_worker = Worker::new(
+        train_sample::NAME,
+        config.worker.concurrency,
+        queues.train_voice_queue_url.to_string(),
+        queues.train_voice_dead_letter_queue_url.clone(),
+    )
+    .await;
+    let visibility_timeout = Duration::from_secs(config.worker.visibility_timeout_secs);
+    let mut terminate = signal(SignalKind::terminate())?;
+    let (shutdown, stopped) = watch::channel(false);
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = terminate.recv() => {},
+            _ = tokio::signal::ctrl_c() => {},
+        }
+        tracing::info!("shutting down, finishing batches in flight");
+        shutdown.send(true).ok();
+    });
+    tokio::join!(
+        create_output_worker.poll(
+            visibility_timeout,
+            |message| {
+                create_output::process(
+                    message,
+                    &eleven_labs,
+                    &outputs_bucket,
+                    config.worker.max_receive_count,
+                )
+            },
+            stopped.clone(),
+        ),
+        train_sample_worker.poll(
+            visibility_timeout,
+            |message| train_sample::process(message, &eleven_labs, &samples_bucket),
+            stopped,
+        ),
+    );
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use lambda_web::actix_web::{web, HttpResponse};
+use mongoose::{
+    bson::{doc, to_bson, Document},
+    types::ListOptions,
+    Model,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::ApiResponse,
+    helpers::{parse_date, Identity},
+    models::{
+        api_key::Scope,
+        audit_event::{AuditAction, AuditEvent},
+    },
+};
+
+#[derive(Deserialize, Serialize)]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    pub action: Option<AuditAction>,
+    pub target: Option<String>,
+    pub request_id: Option<String>,
+    // rfc 3339 timestamps
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub limit: Option<i64>,
+    pub skip: Option<u64>,
+}
+
+pub async fn list_audit_events(identity: Identity, query: web::Query<AuditQuery>) -> ApiResponse {
+    identity.authorize(Scope::Admin)?;
+    let mut filter = doc! { "workspace": &identity.workspace };
+    if let Some(actor) = &query.actor {
+        filter.insert("actor", actor);
+    }
+    if let Some(action) = &query.action {
+        filter.insert("action", to_bson(action)?);
+    }
+    if let Some(target) = &query.target {
+        filter.insert("target", target);
+    }
+    if let Some(request_id) = &query.request_id {
+        filter.insert("request_id", request_id);
+    }
+    let mut created_at = Document::new();
+    if let Some(from) = &query.from {
+        created_at.insert("$gte", parse_date(from)?);
+    }
+    if let Some(to) = &query.to {
+        created_at.insert("$lt", parse_date(to)?);
+    }
+    if !created_at.is_empty() {
+        filter.insert("created_at", created_at);
+    }
+    let options = ListOptions {
+        limit: Some(query.limit.unwrap_or(100).clamp(1, 1_000)),
+        skip: query.skip,
+        sort: Some(doc! { "created_at": -1 }),
+    };
+    let events = AuditEvent::list(Some(filter), Some(options)).await?;
+    Ok(HttpResponse::Ok().json(events))
+}
@@ -0,0 +1,7 @@
+use lambda_web::actix_web::web::{self, ServiceConfig};
+
+mod controller;
+
+pub fn router(cfg: &mut ServiceConfig) {
+    cfg.route("", web::get().to(controller::list_audit_events));
+}
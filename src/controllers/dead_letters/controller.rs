@@ -0,0 +1,245 @@
+use std::time::{Duration, Instant};
+
+use lambda_web::actix_web::{web, HttpResponse};
+use mongoose::{bson::doc, types::MongooseError, Model};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    aws::sqs::{DeadLetterQueue, ReceivedMessage},
+    env::Config,
+    errors::{ApiResponse, AppError},
+    helpers::Identity,
+    models::{
+        output::Output,
+        voice::{Voice, VoiceStatus},
+    },
+    types::{next_attempt, CreateOutputFifoMessage, Envelope, TrainSampleFifoMessage},
+};
+
+// how many dead letters a redrive looks through for the requested ids
+const REDRIVE_SCAN_LIMIT: usize = 100;
+// one sqs receive
+const REDRIVE_BATCH: usize = 10;
+// the scan stops here to leave time for releasing what it holds inside the lambda timeout
+const REDRIVE_DEADLINE: Duration = Duration::from_secs(20);
+// held while a redrive runs, anything not selected is released right after
+const REDRIVE_VISIBILITY: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeadLetterSource {
+    Outputs,
+    Voices,
+}
+
+impl DeadLetterSource {
+    async fn queue(self, config: &Config) -> anyhow::Result<DeadLetterQueue> {
+        let queues = config.queues()?;
+        let (queue_url, source_queue_url) = match self {
+            Self::Outputs => (
+                &queues.create_output_dead_letter_queue_url,
+                &queues.create_output_queue_url,
+            ),
+            Self::Voices => (
+                &queues.train_voice_dead_letter_queue_url,
+                &queues.train_voice_queue_url,
+            ),
+        };
+        let Some(queue_url) = queue_url else {
+            return Err(AppError::not_found(
+                "dead_letter_queue_not_configured",
+                "no dead letter queue configured",
+            )
+            .into());
+        };
+        Ok(DeadLetterQueue::new(queue_url.to_string(), source_queue_url.to_string()).await)
+    }
+
+    // the document a message refers to, when its body still parses and the document exists
+    async fn document(self, dead_letter: &ReceivedMessage) -> Option<Value> {
+        match self {
+            Self::Outputs => {
+                let envelope = Envelope::<CreateOutputFifoMessage>::open(&dead_letter.body).ok()?;
+                let output = Output::read_by_id(&envelope.payload.output_id).await.ok()?;
+                serde_json::to_value(output).ok()
+            }
+            Self::Voices => {
+                let envelope = Envelope::<TrainSampleFifoMessage>::open(&dead_letter.body).ok()?;
+                let voice = Voice::read_by_id(&envelope.payload.voice_id).await.ok()?;
+                serde_json::to_value(voice).ok()
+            }
+        }
+    }
+
+    // a voice failed by the worker is training again once its message is back on the queue,
+    // and an output the worker refunded is charged again
+    async fn redriven(self, dead_letter: &ReceivedMessage) -> anyhow::Result<()> {
+        match self {
+            Self::Outputs => {
+                let Ok(envelope) = Envelope::<CreateOutputFifoMessage>::open(&dead_letter.body)
+                else {
+                    return Ok(());
+                };
+                match Output::read_by_id(&envelope.payload.output_id).await {
+                    Ok(output) => output.recharge_characters().await,
+                    Err(MongooseError::NotFound(_)) => Ok(()),
+                    Err(err) => Err(err.into()),
+                }
+            }
+            Self::Voices => {
+                let Ok(envelope) = Envelope::<TrainSampleFifoMessage>::open(&dead_letter.body)
+                else {
+                    return Ok(());
+                };
+                match Voice::update(
+                    doc! {
+                        "_id": envelope.payload.voice_id,
+                        "status": VoiceStatus::Failed.to_string(),
+                    },
+                    doc! { "status": VoiceStatus::Training.to_string() },
+                )
+                .await
+                {
+                    Ok(_) | Err(MongooseError::NotFound(_)) => Ok(()),
+                    Err(err) => Err(err.into()),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct DeadLetterEntry {
+    #[serde(flatten)]
+    pub message: ReceivedMessage,
+    pub document: Option<Value>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct DeadLetterQuery {
+    pub limit: Option<usize>,
+}
+
+pub async fn list_dead_letters(
+    identity: Identity,
+    config: web::Data<Config>,
+    source: web::Path<DeadLetterSource>,
+    query: web::Query<DeadLetterQuery>,
+) -> ApiResponse {
+    identity.require_root()?;
+    let queue = source.queue(&config).await?;
+    let limit = query.limit.unwrap_or(50).clamp(1, 100);
+    let mut entries = vec![];
+    for message in queue.peek(limit).await? {
+        entries.push(DeadLetterEntry {
+            document: source.document(&message).await,
+            message,
+        });
+    }
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RedrivePayload {
+    pub message_ids: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct RedriveFailure {
+    pub message_id: String,
+    pub error: String,
+}
+
+pub async fn redrive_dead_letters(
+    identity: Identity,
+    config: web::Data<Config>,
+    source: web::Path<DeadLetterSource>,
+    body: web::Json<RedrivePayload>,
+) -> ApiResponse {
+    identity.require_root()?;
+    if body.message_ids.is_empty() {
+        return Err(AppError::validation("message_ids_empty", "no message ids supplied").into());
+    }
+    let queue = source.queue(&config).await?;
+    let started = Instant::now();
+    let (mut redriven, mut failed, mut held) = (vec![], vec![], vec![]);
+    let (mut scanned, mut receive_error) = (0, None);
+    // messages not selected stay held so the next receive moves on past them, nothing returns
+    // early until they are released
+    loop {
+        if scanned >= REDRIVE_SCAN_LIMIT
+            || redriven.len() + failed.len() >= body.message_ids.len()
+            || started.elapsed() >= REDRIVE_DEADLINE
+        {
+            break;
+        }
+        let limit = REDRIVE_BATCH.min(REDRIVE_SCAN_LIMIT - scanned);
+        let dead_letters = match queue.receive(limit, REDRIVE_VISIBILITY).await {
+            Ok(dead_letters) if dead_letters.is_empty() => break,
+            Ok(dead_letters) => dead_letters,
+            Err(err) => {
+                receive_error = Some(err);
+                break;
+            }
+        };
+        scanned += dead_letters.len();
+        for dead_letter in dead_letters {
+            if !body.message_ids.contains(&dead_letter.message_id) {
+                held.push(dead_letter);
+                continue;
+            }
+            if let Err(err) = queue
+                .redrive(&dead_letter, &next_attempt(&dead_letter.body))
+                .await
+            {
+                tracing::error!(
+                    "error redriving dead letter {}: {err:?}",
+                    dead_letter.message_id
+                );
+                failed.push(RedriveFailure {
+                    message_id: dead_letter.message_id.to_string(),
+                    error: err.to_string(),
+                });
+                // left on the dead letter queue to be redriven again
+                held.push(dead_letter);
+                continue;
+            }
+            // already back on the queue, so only the document is out of step
+            if let Err(err) = source.redriven(&dead_letter).await {
+                tracing::error!(
+                    "error updating redriven {}: {err:?}",
+                    dead_letter.message_id
+                );
+                failed.push(RedriveFailure {
+                    message_id: dead_letter.message_id,
+                    error: format!("redriven, but its document was not updated: {err}"),
+                });
+                continue;
+            }
+            tracing::info!("redrove dead letter {}", dead_letter.message_id);
+            redriven.push(dead_letter.message_id);
+        }
+    }
+    // they become visible again once the redrive visibility runs out either way
+    if let Err(err) = queue.release(&held).await {
+        tracing::error!("error releasing held dead letters: {err:?}");
+    }
+    if let Some(err) = receive_error {
+        if redriven.is_empty() && failed.is_empty() {
+            return Err(err.into());
+        }
+        tracing::error!("error receiving dead letters, redrive stopped early: {err:?}");
+    }
+    let missing = body
+        .message_ids
+        .iter()
+        .filter(|id| !redriven.contains(id))
+        .filter(|id| !failed.iter().any(|failure| &failure.message_id == *id))
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(json!({
+        "redriven": redriven,
+        "failed": failed,
+        "missing": missing,
+    })))
+}
@@ -0,0 +1,11 @@
+use lambda_web::actix_web::web::{self, ServiceConfig};
+
+mod controller;
+
+pub fn router(cfg: &mut ServiceConfig) {
+    cfg.route("/{queue}", web::get().to(controller::list_dead_letters));
+    cfg.route(
+        "/{queue}/redrive",
+        web::post().to(controller::redrive_dead_letters),
+    );
+}
@@ -0,0 +1,115 @@
+use lambda_web::actix_web::{web, HttpResponse};
+use mongoose::{
+    bson::{doc, DateTime},
+    Model,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    errors::{ApiResponse, AppError},
+    helpers::{Identity, RequestId},
+    models::{
+        api_key::{ApiKey, Scope},
+        audit_event::{AuditAction, AuditEvent},
+        insert,
+    },
+    quota::Limits,
+};
+
+// keys that need to live longer than ten years should not expire at all
+const MAX_EXPIRES_IN_DAYS: i64 = 3650;
+
+#[derive(Deserialize, Serialize)]
+pub struct CreateKeyBody {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub expires_in_days: Option<i64>,
+    #[serde(default)]
+    pub limits: Limits,
+}
+
+pub async fn create_key(
+    identity: Identity,
+    request_id: RequestId,
+    body: web::Json<CreateKeyBody>,
+) -> ApiResponse {
+    identity.authorize(Scope::Admin)?;
+    if body.name.trim().is_empty() {
+        return Err(AppError::validation("key_name_empty", "key name is empty").into());
+    }
+    if body.scopes.is_empty() {
+        return Err(AppError::validation("key_scopes_empty", "key has no scopes").into());
+    }
+    if let Some(days) = body.expires_in_days {
+        if !(1..=MAX_EXPIRES_IN_DAYS).contains(&days) {
+            return Err(AppError::validation(
+                "key_expiry_invalid",
+                format!("key expiry must be between 1 and {MAX_EXPIRES_IN_DAYS} days"),
+            )
+            .into());
+        }
+    }
+    let secret = ApiKey::generate_secret();
+    let expires_at = body.expires_in_days.map(|days| {
+        DateTime::from_millis(DateTime::now().timestamp_millis() + days * 24 * 60 * 60 * 1000)
+    });
+    let key = insert(&ApiKey {
+        workspace: identity.workspace.to_string(),
+        name: body.name.trim().to_string(),
+        prefix: secret.chars().take(8).collect(),
+        hash: ApiKey::hash_secret(&secret),
+        scopes: body.scopes.clone(),
+        limits: body.limits.clone(),
+        expires_at,
+        ..Default::default()
+    })
+    .await?;
+    AuditEvent::record(
+        &identity.workspace,
+        &identity.actor(),
+        AuditAction::ApiKeyCreated,
+        format!("key:{}", key.id),
+        Some(&request_id.0),
+    )
+    .await?;
+    // the secret is only ever returned here
+    Ok(HttpResponse::Created().json(json!({ "key": key.redacted(), "secret": secret })))
+}
+
+pub async fn list_keys(identity: Identity) -> ApiResponse {
+    identity.authorize(Scope::Admin)?;
+    let keys = ApiKey::list(Some(doc! { "workspace": &identity.workspace }), None)
+        .await?
+        .into_iter()
+        .map(ApiKey::redacted)
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(keys))
+}
+
+pub async fn revoke_key(
+    identity: Identity,
+    request_id: RequestId,
+    key_id: web::Path<String>,
+) -> ApiResponse {
+    identity.authorize(Scope::Admin)?;
+    let key =
+        ApiKey::read(doc! { "_id": key_id.as_str(), "workspace": &identity.workspace }).await?;
+    if key.revoked_at.is_some() {
+        return Err(AppError::conflict("key_revoked", "key is already revoked").into());
+    }
+    let key = ApiKey::update(
+        doc! { "_id": key.id },
+        doc! { "revoked_at": DateTime::now() },
+    )
+    .await?;
+    AuditEvent::record(
+        &identity.workspace,
+        &identity.actor(),
+        AuditAction::ApiKeyRevoked,
+        format!("key:{}", key.id),
+        Some(&request_id.0),
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(key.redacted()))
+}
@@ -0,0 +1,9 @@
+use lambda_web::actix_web::web::{self, ServiceConfig};
+
+mod controller;
+
+pub fn router(cfg: &mut ServiceConfig) {
+    cfg.route("", web::post().to(controller::create_key));
+    cfg.route("", web::get().to(controller::list_keys));
+    cfg.route("/{id}", web::delete().to(controller::revoke_key));
+}
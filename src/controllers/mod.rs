@@ -1,10 +1,20 @@
 use lambda_web::actix_web::web::{scope, ServiceConfig};
+mod audit;
+mod dead_letters;
+mod keys;
 mod outputs;
 mod samples;
+mod usage;
 mod voices;
+mod workspaces;
 
 pub fn routes(cfg: &mut ServiceConfig) {
     cfg.service(scope("/samples").configure(samples::router));
     cfg.service(scope("/voices").configure(voices::router));
     cfg.service(scope("/outputs").configure(outputs::router));
+    cfg.service(scope("/keys").configure(keys::router));
+    cfg.service(scope("/workspaces").configure(workspaces::router));
+    cfg.service(scope("/usage").configure(usage::router));
+    cfg.service(scope("/audit").configure(audit::router));
+    cfg.service(scope("/dead-letters").configure(dead_letters::router));
 }
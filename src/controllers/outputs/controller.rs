@@ -1,6 +1,6 @@
 use std::time::Duration;
 
-use lambda_web::actix_web::{web, HttpRequest, HttpResponse};
+use lambda_web::actix_web::{web, HttpResponse};
 use mongoose::{bson::doc, Model};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
@@ -10,53 +10,118 @@ use crate::{
         s3::Client,
         sqs::{FifoMessage, FifoQueue},
     },
-    env::{self, Config},
-    errors::ApiResponse,
-    helpers::authenticate,
+    env::Config,
+    errors::{ApiResponse, AppError},
+    helpers::{Identity, RequestId},
     models::{
+        api_key::Scope,
+        audit_event::{AuditAction, AuditEvent},
         output::Output,
         voice::{Voice, VoiceStatus},
     },
-    types::CreateOutputFifoMessage,
+    quota,
+    types::{CreateOutputFifoMessage, Envelope},
 };
 
+const CHARACTER_LIMIT_HEADER: &str = "X-Character-Quota-Limit";
+const CHARACTER_REMAINING_HEADER: &str = "X-Character-Quota-Remaining";
+
 #[derive(Deserialize, Serialize)]
 pub struct OutputPayload {
     voice_id: String,
     text: String,
 }
 
-pub async fn create_output(req: HttpRequest, body: web::Json<OutputPayload>) -> ApiResponse {
-    authenticate(req).await?;
+pub async fn create_output(
+    identity: Identity,
+    config: web::Data<Config>,
+    request_id: RequestId,
+    body: web::Json<OutputPayload>,
+) -> ApiResponse {
+    identity.authorize(Scope::OutputsCreate)?;
     if body.text.chars().count() >= 250 {
-        return Ok(HttpResponse::BadRequest()
-            .json(json!({ "error": "text length greater than 250 characters" })));
+        return Err(AppError::Validation {
+            code: "text_too_long",
+            error: "text length greater than 250 characters".to_string(),
+            details: Some(json!({ "max_length": 250 })),
+        }
+        .into());
     }
-    let voice = match Voice::read_by_id(&body.voice_id).await {
-        Ok(voice) => voice,
-        Err(_) => return Ok(HttpResponse::NotFound().json(json!({ "error": "no voice found" }))),
+    let Ok(voice) = Voice::read_in_workspace(&identity.workspace, &body.voice_id).await else {
+        return Err(AppError::not_found("voice_not_found", "no voice found").into());
     };
     if voice.status != VoiceStatus::Active {
-        return Ok(HttpResponse::BadRequest().json(json!({ "error": "voice is not active" })));
+        return Err(AppError::conflict("voice_not_active", "voice is not active").into());
     }
+    let text = body.text.trim().to_string();
+    let characters = u64::try_from(text.chars().count())?;
+    let quotas = match quota::consume_characters(&identity.quotas, characters).await? {
+        Ok(quotas) => quotas,
+        Err(exceeded) => {
+            return Err(AppError::QuotaExceeded {
+                error: format!(
+                    "{} {} character quota reached",
+                    exceeded.subject, exceeded.window
+                ),
+                limit: exceeded.limit,
+                retry_after: exceeded.reset_after,
+            }
+            .into());
+        }
+    };
     let output = Output {
+        actor: identity.actor(),
+        workspace: identity.workspace.to_string(),
         voice: voice.id,
-        text: body.text.trim().to_string(),
+        text,
+        charged_counters: quotas
+            .iter()
+            .map(|quota| quota.counter.to_string())
+            .collect(),
         ..Default::default()
     };
-    let output = output.save().await?;
-    let config = Config::new()?;
-    let sqs = FifoQueue::new(config.create_output_queue_url).await;
-    // push to FIFO
-    sqs.send_fifo_message::<CreateOutputFifoMessage>(FifoMessage {
-        body: CreateOutputFifoMessage {
-            output_id: output.id.to_string(),
-        },
-        group: output.voice.to_string(),
-        deduplication_id: output.id.to_string(),
-    })
-    .await?;
-    Ok(HttpResponse::Created().json(output))
+    if let Err(err) = output.save().await {
+        quota::refund_characters(&output.charged_counters, characters).await?;
+        return Err(err.into());
+    }
+    let queued: anyhow::Result<()> = async {
+        AuditEvent::record(
+            &output.workspace,
+            &identity.actor(),
+            AuditAction::OutputRequested,
+            format!("output:{}", output.id),
+            Some(&request_id.0),
+        )
+        .await?;
+        let sqs = FifoQueue::new(config.queues()?.create_output_queue_url.to_string()).await;
+        // push to FIFO
+        sqs.send_fifo_message(FifoMessage {
+            body: Envelope::new(
+                CreateOutputFifoMessage {
+                    output_id: output.id.to_string(),
+                },
+                &identity.actor(),
+                Some(&request_id.0),
+            ),
+            group: output.voice.to_string(),
+            deduplication_id: output.id.to_string(),
+        })
+        .await?;
+        Ok(())
+    }
+    .await;
+    // an output that never reaches the queue is never made
+    if let Err(err) = queued {
+        output.refund_characters().await?;
+        return Err(err.into());
+    }
+    let mut response = HttpResponse::Created();
+    if let Some(quota) = quota::tightest(&quotas) {
+        response
+            .insert_header((CHARACTER_LIMIT_HEADER, quota.limit))
+            .insert_header((CHARACTER_REMAINING_HEADER, quota.remaining));
+    }
+    Ok(response.json(output))
 }
 
 #[derive(Deserialize, Serialize)]
@@ -65,27 +130,29 @@ pub struct SearchOutputTextPayload {
 }
 
 pub async fn search_outputs_text(
-    req: HttpRequest,
+    identity: Identity,
     body: web::Json<SearchOutputTextPayload>,
 ) -> ApiResponse {
-    authenticate(req).await?;
-    let results = Output::search_text(&body.text).await?;
+    identity.authorize(Scope::OutputsCreate)?;
+    let results = Output::search_text(&identity.workspace, &body.text).await?;
     Ok(HttpResponse::Ok().json(results))
 }
 
-pub async fn get_output_presigned(req: HttpRequest, id: web::Path<String>) -> ApiResponse {
-    authenticate(req).await?;
-    let output = Output::read_by_id(&id).await?;
-    let config = env::Config::new()?;
-    let s3 = Client::new(&config.outputs_bucket_name).await;
-    let key = format!("{}.mp3", output.id);
+pub async fn get_output_presigned(
+    identity: Identity,
+    config: web::Data<Config>,
+    id: web::Path<String>,
+) -> ApiResponse {
+    identity.authorize(Scope::OutputsCreate)?;
+    let output = Output::read_in_workspace(&identity.workspace, &id).await?;
+    let s3 = Client::new(&config.buckets()?.outputs_bucket_name).await;
     let expires = Duration::from_secs(120);
-    let url = s3.get_presigned_url(&key, expires).await?;
+    let url = s3.get_presigned_url(&output.object_key(), expires).await?;
     Ok(HttpResponse::Ok().json(json!({ "url": url })))
 }
 
-pub async fn get_output(req: HttpRequest, id: web::Path<String>) -> ApiResponse {
-    authenticate(req).await?;
-    let output = Output::read_by_id(&id).await?;
+pub async fn get_output(identity: Identity, id: web::Path<String>) -> ApiResponse {
+    identity.authorize(Scope::OutputsCreate)?;
+    let output = Output::read_in_workspace(&identity.workspace, &id).await?;
     Ok(HttpResponse::Ok().json(output))
 }
@@ -1,49 +1,152 @@
 use std::time::Duration;
 
-use lambda_web::actix_web::{web, HttpRequest, HttpResponse};
-use mongoose::{bson::doc, Model};
+use lambda_web::actix_web::{web, HttpResponse};
+use mongoose::{
+    bson::{doc, DateTime},
+    Model,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
 use crate::{
-    aws::s3::Client, env, errors::ApiResponse, helpers::authenticate, models::voice::Voice,
+    aws::s3::Client,
+    env::Config,
+    errors::{ApiResponse, AppError},
+    helpers::{parse_date, Identity, RequestId},
+    models::{
+        api_key::Scope,
+        audit_event::{AuditAction, AuditEvent},
+        consent::Consent,
+        insert,
+        voice::Voice,
+        workspace::Workspace,
+    },
 };
 
+#[derive(Deserialize, Serialize)]
+pub struct ConsentBody {
+    pub speaker_name: String,
+    pub statement: String,
+    pub signer: String,
+    // rfc 3339, defaults to now
+    pub signed_at: Option<String>,
+    // returns a second upload url for a recording of the speaker giving consent
+    #[serde(default)]
+    pub recording: bool,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct UploadSampleBody {
     pub voice_name: String,
     pub description: Option<String>,
+    pub consent: Option<ConsentBody>,
 }
 
-pub async fn request_put_url(req: HttpRequest, body: web::Json<UploadSampleBody>) -> ApiResponse {
-    authenticate(req).await?;
-    let config = env::Config::new()?;
-    let s3 = Client::new(&config.samples_bucket_name).await;
-    let name = slug::slugify(body.voice_name.to_string());
-    let count = Voice::active_voices_count().await?;
-    if count >= 10 {
-        return Ok(HttpResponse::BadRequest().json(json!({ "error": "10 voice limit reached" })));
+pub async fn request_put_url(
+    identity: Identity,
+    config: web::Data<Config>,
+    request_id: RequestId,
+    body: web::Json<UploadSampleBody>,
+) -> ApiResponse {
+    identity.authorize(Scope::VoicesWrite)?;
+    let Some(consent) = &body.consent else {
+        return Err(AppError::validation(
+            "consent_required",
+            "consent is required to clone a voice",
+        )
+        .into());
+    };
+    let fields = [&consent.speaker_name, &consent.statement, &consent.signer];
+    if fields.iter().any(|field| field.trim().is_empty()) {
+        return Err(AppError::validation(
+            "consent_incomplete",
+            "consent requires a speaker name, statement and signer",
+        )
+        .into());
     }
-    match Voice::read(doc! { "name": &name }).await {
-        Ok(_) => {
-            return Ok(
-                HttpResponse::BadRequest().json(json!({ "error": "voice with name is taken" }))
-            )
-        }
-        Err(_) => (),
+    let signed_at = match &consent.signed_at {
+        Some(signed_at) => parse_date(signed_at)?,
+        None => DateTime::now(),
     };
-    let description = body
-        .description
-        .as_ref()
-        .map_or(None, |desc| Some(desc.to_string()));
-    let voice = Voice {
+    let s3 = Client::new(&config.buckets()?.samples_bucket_name).await;
+    let name = slug::slugify(&body.voice_name);
+    let workspace = Workspace::read_by_id(&identity.workspace).await?;
+    let count = Voice::active_voices_count(&workspace.id).await?;
+    if count >= workspace.voice_limit {
+        return Err(AppError::Conflict {
+            code: "voice_limit_reached",
+            error: format!("{} voice limit reached", workspace.voice_limit),
+            details: Some(json!({ "limit": workspace.voice_limit })),
+        }
+        .into());
+    }
+    if Voice::name_taken(&workspace.id, &name).await {
+        return Err(AppError::conflict("voice_name_taken", "voice with name is taken").into());
+    }
+    let description = body.description.as_ref().map(ToString::to_string);
+    let voice = insert(&Voice {
+        workspace: workspace.id,
         name,
         description,
+        actor: identity.actor(),
+        ..Default::default()
+    })
+    .await?;
+    let recording_key = consent
+        .recording
+        .then(|| Consent::recording_key(&voice.workspace, &voice.id));
+    let saved = Consent {
+        workspace: voice.workspace.to_string(),
+        voice: voice.id.to_string(),
+        speaker_name: consent.speaker_name.trim().to_string(),
+        statement: consent.statement.trim().to_string(),
+        signer: consent.signer.trim().to_string(),
+        signed_at,
+        recording_key,
+        actor: identity.actor(),
         ..Default::default()
     }
     .save()
+    .await;
+    let consent = match saved {
+        Ok(consent) => consent,
+        Err(err) => {
+            // a voice without consent could never train, and would hold on to its name
+            if let Err(delete_err) = Voice::delete(doc! { "_id": &voice.id }).await {
+                tracing::error!(
+                    "error deleting voice {} without consent: {delete_err:?}",
+                    voice.id
+                );
+            }
+            return Err(err.into());
+        }
+    };
+    AuditEvent::record(
+        &voice.workspace,
+        &identity.actor(),
+        AuditAction::SampleUploadRequested,
+        format!("voice:{}", voice.id),
+        Some(&request_id.0),
+    )
+    .await?;
+    AuditEvent::record(
+        &voice.workspace,
+        &identity.actor(),
+        AuditAction::ConsentRecorded,
+        format!("consent:{}", consent.id),
+        Some(&request_id.0),
+    )
     .await?;
-    let key = format!("{}.mp3", voice.id);
-    let url = s3.put_presigned_url(&key, Duration::from_secs(120)).await?;
-    Ok(HttpResponse::Ok().json(json!({ "url": url, "voice": voice })))
+    let expires = Duration::from_secs(120);
+    let url = s3.put_presigned_url(&voice.sample_key(), expires).await?;
+    let consent_url = match &consent.recording_key {
+        Some(key) => Some(s3.put_presigned_url(key, expires).await?),
+        None => None,
+    };
+    Ok(HttpResponse::Ok().json(json!({
+        "url": url,
+        "consent_url": consent_url,
+        "voice": voice,
+        "consent": consent,
+    })))
 }
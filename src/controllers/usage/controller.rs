@@ -0,0 +1,91 @@
+use chrono::{Datelike, TimeZone, Utc};
+use lambda_web::actix_web::{http::header, web, HttpResponse};
+use mongoose::bson::DateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    eleven_labs::ElevenLabs,
+    errors::ApiResponse,
+    helpers::{parse_date, Identity},
+    models::{
+        api_key::Scope,
+        usage_event::{UsageEvent, UsageGroup, UsageSummary},
+    },
+};
+
+#[derive(Deserialize, Serialize)]
+pub struct UsageQuery {
+    // rfc 3339 timestamps, defaulting to the current month
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub group_by: Option<UsageGroup>,
+    pub format: Option<String>,
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        return format!("\"{}\"", value.replace('"', "\"\""));
+    }
+    value.to_string()
+}
+
+fn to_csv(summaries: &[UsageSummary]) -> String {
+    let mut csv = "group,characters,outputs,clones\n".to_string();
+    for summary in summaries {
+        csv.push_str(&format!(
+            "{},{},{},{}\n",
+            csv_field(&summary.group),
+            summary.characters,
+            summary.outputs,
+            summary.clones
+        ));
+    }
+    csv
+}
+
+pub async fn get_usage(identity: Identity, query: web::Query<UsageQuery>) -> ApiResponse {
+    identity.authorize(Scope::Admin)?;
+    let now = Utc::now();
+    let from = match &query.from {
+        Some(from) => parse_date(from)?,
+        None => {
+            let start = Utc.with_ymd_and_hms(now.year(), now.month(), 1, 0, 0, 0);
+            DateTime::from_chrono(start.single().unwrap_or(now))
+        }
+    };
+    let to = match &query.to {
+        Some(to) => parse_date(to)?,
+        None => DateTime::from_chrono(now),
+    };
+    let group = query.group_by.unwrap_or(UsageGroup::Day);
+    let summaries = UsageEvent::summarize(&identity.workspace, from, to, group).await?;
+    if query.format.as_deref() == Some("csv") {
+        return Ok(HttpResponse::Ok()
+            .insert_header((header::CONTENT_TYPE, "text/csv"))
+            .insert_header((
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"usage.csv\"",
+            ))
+            .body(to_csv(&summaries)));
+    }
+    Ok(HttpResponse::Ok().json(json!({ "from": from, "to": to, "usage": summaries })))
+}
+
+pub async fn get_subscription(
+    identity: Identity,
+    eleven_labs: web::Data<ElevenLabs>,
+) -> ApiResponse {
+    identity.authorize(Scope::Admin)?;
+    let subscription = eleven_labs.get_subscription().await?;
+    let remaining = subscription
+        .character_limit
+        .saturating_sub(subscription.character_count);
+    Ok(HttpResponse::Ok().json(json!({
+        "tier": subscription.tier,
+        "character_count": subscription.character_count,
+        "character_limit": subscription.character_limit,
+        "remaining_characters": remaining,
+        "next_character_count_reset_unix": subscription.next_character_count_reset_unix,
+    })))
+}
@@ -0,0 +1,8 @@
+use lambda_web::actix_web::web::{self, ServiceConfig};
+
+mod controller;
+
+pub fn router(cfg: &mut ServiceConfig) {
+    cfg.route("", web::get().to(controller::get_usage));
+    cfg.route("/subscription", web::get().to(controller::get_subscription));
+}
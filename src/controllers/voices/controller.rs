@@ -1,62 +1,477 @@
-use lambda_web::actix_web::{web, HttpRequest, HttpResponse};
-use mongoose::{bson::doc, Model};
+use std::collections::HashMap;
+
+use lambda_web::actix_web::{web, HttpResponse};
+use mongoose::{
+    bson::{doc, to_bson, DateTime, Document},
+    Model,
+};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 
 use crate::{
-    eleven_labs::ElevenLabs,
-    errors::ApiResponse,
-    helpers::authenticate,
-    models::voice::{Voice, VoiceStatus},
+    aws::{
+        s3::Client,
+        sqs::{FifoMessage, FifoQueue},
+    },
+    eleven_labs::{ElevenLabs, ProviderError, VoiceSettings},
+    env::Config,
+    errors::{ApiResponse, AppError},
+    helpers::{Identity, RequestId},
+    models::{
+        api_key::Scope,
+        audit_event::{AuditAction, AuditEvent},
+        consent::Consent,
+        insert,
+        provider_voice::ProviderVoice,
+        update,
+        voice::{Voice, VoiceStatus},
+        workspace::Workspace,
+    },
+    types::{Envelope, TrainSampleFifoMessage},
 };
 
-pub async fn list_voices(req: HttpRequest) -> ApiResponse {
-    authenticate(req).await?;
-    let voices = Voice::list(None, None).await?;
+pub async fn list_voices(identity: Identity) -> ApiResponse {
+    identity.authorize(Scope::VoicesRead)?;
+    let voices = Voice::list(Some(doc! { "workspace": &identity.workspace }), None).await?;
     Ok(HttpResponse::Ok().json(voices))
 }
 
-pub async fn get_voice_by_id(req: HttpRequest, voice_id: web::Path<String>) -> ApiResponse {
-    authenticate(req).await?;
-    let voice = Voice::read_by_id(&voice_id).await?;
+pub async fn get_voice_by_id(identity: Identity, voice_id: web::Path<String>) -> ApiResponse {
+    identity.authorize(Scope::VoicesRead)?;
+    let voice = Voice::read_in_workspace(&identity.workspace, &voice_id).await?;
     Ok(HttpResponse::Ok().json(voice))
 }
 
-pub async fn delete_voice(req: HttpRequest, voice_id: web::Path<String>) -> ApiResponse {
-    authenticate(req).await?;
-    let voice = Voice::read_by_id(&voice_id).await?;
-    if voice.status != VoiceStatus::Active {
-        return Ok(
-            HttpResponse::InternalServerError().json(json!({ "error": "voice is not active" }))
-        );
+pub async fn delete_voice(
+    identity: Identity,
+    config: web::Data<Config>,
+    eleven_labs: web::Data<ElevenLabs>,
+    request_id: RequestId,
+    voice_id: web::Path<String>,
+) -> ApiResponse {
+    identity.authorize(Scope::VoicesWrite)?;
+    let voice = Voice::read_in_workspace(&identity.workspace, &voice_id).await?;
+    match voice.status {
+        // failed and draft voices still hold their name until they are deleted
+        VoiceStatus::Active | VoiceStatus::Failed | VoiceStatus::Draft => (),
+        VoiceStatus::Training => {
+            return Err(AppError::conflict("voice_training", "voice is still training").into())
+        }
+        VoiceStatus::Deleted => {
+            return Err(AppError::conflict("voice_deleted", "voice is deleted").into())
+        }
     }
     let eleven_labs_id = match voice.eleven_labs_id {
-        Some(id) => id,
-        None => {
-            return Ok(HttpResponse::InternalServerError()
-                .json(json!({ "error": "voice has no id attached" })))
+        // externally managed voices stay linked, they are owned by the eleven labs account
+        Some(eleven_labs_id) if voice.externally_managed => Some(eleven_labs_id),
+        Some(eleven_labs_id) => {
+            // delete voice from eleven labs
+            match eleven_labs.delete_voice(&eleven_labs_id).await {
+                // already gone upstream, nothing left to delete
+                Ok(()) | Err(ProviderError::VoiceNotFound { .. }) => (),
+                Err(err) => return Err(err.into()),
+            }
+            None
         }
+        // never cloned, so there is nothing upstream
+        None => None,
     };
-    // delete voice from eleven labs
-    let eleven_labs = ElevenLabs::new()?;
-    let deleted = match eleven_labs.delete_voice(&eleven_labs_id).await {
-        Ok(status) => status.as_u16(),
-        Err(err) => {
-            return Ok(HttpResponse::InternalServerError().json(json!({ "error": err.to_string() })))
+    // the sample is retained so the voice can be restored until it is purged
+    let deleted_at = DateTime::now();
+    let retention_days = config.retention.voice_retention_days;
+    let purge_after = retention_days
+        .checked_mul(24 * 60 * 60 * 1000)
+        .and_then(|retention_ms| deleted_at.timestamp_millis().checked_add(retention_ms))
+        .map(DateTime::from_millis)
+        .ok_or_else(|| {
+            anyhow::anyhow!("voice retention of {retention_days} days is out of range")
+        })?;
+    let voice = Voice::update(
+        doc! { "_id": &voice.id },
+        doc! {
+            "status": VoiceStatus::Deleted.to_string(),
+            "eleven_labs_id": eleven_labs_id,
+            "deleted_at": deleted_at,
+            "purge_after": purge_after,
+        },
+    )
+    .await?;
+    AuditEvent::record(
+        &voice.workspace,
+        &identity.actor(),
+        AuditAction::VoiceDeleted,
+        format!("voice:{}", voice.id),
+        Some(&request_id.0),
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(voice))
+}
+
+pub async fn restore_voice(
+    identity: Identity,
+    config: web::Data<Config>,
+    request_id: RequestId,
+    voice_id: web::Path<String>,
+) -> ApiResponse {
+    identity.authorize(Scope::VoicesWrite)?;
+    let voice = Voice::read_in_workspace(&identity.workspace, &voice_id).await?;
+    if voice.status != VoiceStatus::Deleted {
+        return Err(AppError::conflict("voice_not_deleted", "voice is not deleted").into());
+    }
+    let retained = voice
+        .purge_after
+        .is_some_and(|purge_after| purge_after > DateTime::now());
+    if !retained {
+        return Err(
+            AppError::conflict("voice_retention_expired", "voice retention has expired").into(),
+        );
+    }
+    if Voice::name_taken(&voice.workspace, &voice.name).await {
+        return Err(AppError::conflict("voice_name_taken", "voice with name is taken").into());
+    }
+    let workspace = Workspace::read_by_id(&voice.workspace).await?;
+    let count = Voice::active_voices_count(&workspace.id).await?;
+    if count >= workspace.voice_limit {
+        return Err(AppError::Conflict {
+            code: "voice_limit_reached",
+            error: format!("{} voice limit reached", workspace.voice_limit),
+            details: Some(json!({ "limit": workspace.voice_limit })),
         }
-    };
-    if deleted != 200 {
-        return Ok(
-            HttpResponse::InternalServerError().json(json!({ "error": "error deleting voice" }))
+        .into());
+    }
+    let empty_date: Option<DateTime> = None;
+    if voice.externally_managed {
+        // nothing was deleted upstream, so there is nothing to re-clone
+        let voice = update::<Voice>(
+            doc! { "_id": voice.id },
+            doc! {
+                "status": VoiceStatus::Active.to_string(),
+                "deleted_at": empty_date,
+                "purge_after": empty_date,
+            },
+        )
+        .await?;
+        AuditEvent::record(
+            &voice.workspace,
+            &identity.actor(),
+            AuditAction::VoiceRestored,
+            format!("voice:{}", voice.id),
+            Some(&request_id.0),
+        )
+        .await?;
+        return Ok(HttpResponse::Ok().json(voice));
+    }
+    if !Consent::is_verified_for(&voice.id).await {
+        return Err(
+            AppError::conflict("consent_not_verified", "voice has no verified consent").into(),
         );
     }
-    let empty_eleven_labs_id: Option<String> = None;
-    let voice = Voice::update(
-        doc! { "_id": voice_id.to_string() },
+    let s3 = Client::new(&config.buckets()?.samples_bucket_name).await;
+    if s3.get_object(voice.sample_key()).await.is_err() {
+        return Err(AppError::not_found("voice_sample_missing", "voice sample is missing").into());
+    }
+    let voice = update::<Voice>(
+        doc! { "_id": voice.id },
         doc! {
-            "status": VoiceStatus::Deleted.to_string(),
-            "eleven_labs_id": empty_eleven_labs_id
+            "status": VoiceStatus::Training.to_string(),
+            "deleted_at": empty_date,
+            "purge_after": empty_date,
         },
     )
     .await?;
+    // re-clone from the retained sample
+    let sqs = FifoQueue::new(config.queues()?.train_voice_queue_url.to_string()).await;
+    sqs.send_fifo_message(FifoMessage {
+        body: Envelope::new(
+            TrainSampleFifoMessage {
+                voice_id: voice.id.to_string(),
+            },
+            &identity.actor(),
+            Some(&request_id.0),
+        ),
+        group: voice.id.to_string(),
+        deduplication_id: format!("{}-{}", voice.id, voice.updated_at.timestamp_millis()),
+    })
+    .await?;
+    AuditEvent::record(
+        &voice.workspace,
+        &identity.actor(),
+        AuditAction::VoiceRestored,
+        format!("voice:{}", voice.id),
+        Some(&request_id.0),
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(voice))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct UpdateVoiceBody {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub labels: Option<HashMap<String, String>>,
+    pub settings: Option<VoiceSettings>,
+}
+
+pub async fn update_voice(
+    identity: Identity,
+    eleven_labs: web::Data<ElevenLabs>,
+    request_id: RequestId,
+    voice_id: web::Path<String>,
+    body: web::Json<UpdateVoiceBody>,
+) -> ApiResponse {
+    identity.authorize(Scope::VoicesWrite)?;
+    let voice = Voice::read_in_workspace(&identity.workspace, &voice_id).await?;
+    if voice.status == VoiceStatus::Deleted {
+        return Err(AppError::conflict("voice_deleted", "voice is deleted").into());
+    }
+    let mut updates = Document::new();
+    let name = body.name.as_ref().map(slug::slugify);
+    if let Some(name) = &name {
+        if name.is_empty() {
+            return Err(AppError::validation("voice_name_empty", "voice name is empty").into());
+        }
+        if name != &voice.name {
+            if Voice::name_taken(&voice.workspace, name).await {
+                return Err(
+                    AppError::conflict("voice_name_taken", "voice with name is taken").into(),
+                );
+            }
+            updates.insert("name", name);
+        }
+    }
+    if let Some(description) = &body.description {
+        updates.insert("description", description);
+    }
+    if let Some(labels) = &body.labels {
+        updates.insert("labels", to_bson(labels)?);
+    }
+    if let Some(settings) = &body.settings {
+        let out_of_range = settings.out_of_range();
+        if !out_of_range.is_empty() {
+            return Err(AppError::Validation {
+                code: "voice_settings_out_of_range",
+                error: "voice settings must be between 0 and 1".to_string(),
+                details: Some(json!({ "fields": out_of_range })),
+            }
+            .into());
+        }
+        updates.insert("settings", to_bson(settings)?);
+    }
+    if updates.is_empty() {
+        return Ok(HttpResponse::Ok().json(voice));
+    }
+    let provider_changed = updates.contains_key("name")
+        || updates.contains_key("description")
+        || updates.contains_key("labels");
+    if let (true, false, Some(eleven_labs_id)) = (
+        provider_changed,
+        voice.externally_managed,
+        &voice.eleven_labs_id,
+    ) {
+        // keep the provider voice in sync before committing our own changes
+        eleven_labs
+            .edit_voice(
+                eleven_labs_id,
+                name.as_ref().unwrap_or(&voice.name),
+                body.description.as_deref().or(voice.description.as_deref()),
+                body.labels.as_ref().or(voice.labels.as_ref()),
+            )
+            .await?;
+    }
+    let voice = update::<Voice>(doc! { "_id": voice.id }, updates).await?;
+    AuditEvent::record(
+        &voice.workspace,
+        &identity.actor(),
+        AuditAction::VoiceUpdated,
+        format!("voice:{}", voice.id),
+        Some(&request_id.0),
+    )
+    .await?;
     Ok(HttpResponse::Ok().json(voice))
 }
+
+pub async fn list_importable_voices(
+    identity: Identity,
+    eleven_labs: web::Data<ElevenLabs>,
+) -> ApiResponse {
+    identity.authorize(Scope::VoicesRead)?;
+    let provider_voices = eleven_labs.get_voices().await?;
+    // the eleven labs account is shared, so a voice linked in any workspace is not importable
+    let linked = Voice::list(Some(doc! { "eleven_labs_id": { "$ne": null } }), None).await?;
+    let importable = provider_voices
+        .into_iter()
+        .filter(|provider_voice| {
+            !linked
+                .iter()
+                .any(|voice| voice.eleven_labs_id.as_ref() == Some(&provider_voice.voice_id))
+        })
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(importable))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ImportVoicesBody {
+    pub voice_ids: Vec<String>,
+}
+
+pub async fn import_voices(
+    identity: Identity,
+    eleven_labs: web::Data<ElevenLabs>,
+    request_id: RequestId,
+    body: web::Json<ImportVoicesBody>,
+) -> ApiResponse {
+    identity.authorize(Scope::VoicesWrite)?;
+    let provider_voices = eleven_labs.get_voices().await?;
+    let workspace = Workspace::read_by_id(&identity.workspace).await?;
+    let mut count = Voice::active_voices_count(&workspace.id).await?;
+    let mut imported = vec![];
+    let mut skipped = vec![];
+    for voice_id in &body.voice_ids {
+        let Some(provider_voice) = provider_voices
+            .iter()
+            .find(|voice| &voice.voice_id == voice_id)
+        else {
+            skipped.push(json!({
+                "voice_id": voice_id,
+                "code": "provider_voice_not_found",
+                "error": "no provider voice found",
+            }));
+            continue;
+        };
+        if Voice::read(doc! { "eleven_labs_id": voice_id })
+            .await
+            .is_ok()
+        {
+            skipped.push(json!({
+                "voice_id": voice_id,
+                "code": "voice_linked",
+                "error": "voice is already linked to a parrot voice",
+            }));
+            continue;
+        }
+        let name = slug::slugify(&provider_voice.name);
+        if Voice::name_taken(&identity.workspace, &name).await {
+            skipped.push(json!({
+                "voice_id": voice_id,
+                "code": "voice_name_taken",
+                "error": "voice with name is taken",
+            }));
+            continue;
+        }
+        // imported voices are active straight away, so they count against the limit
+        if count >= workspace.voice_limit {
+            skipped.push(json!({
+                "voice_id": voice_id,
+                "code": "voice_limit_reached",
+                "error": format!("{} voice limit reached", workspace.voice_limit),
+            }));
+            continue;
+        }
+        let voice = insert(&Voice {
+            workspace: workspace.id.to_string(),
+            name,
+            status: VoiceStatus::Active,
+            description: provider_voice.description.clone(),
+            eleven_labs_id: Some(provider_voice.voice_id.clone()),
+            externally_managed: true,
+            actor: identity.actor(),
+            ..Default::default()
+        })
+        .await?;
+        ProviderVoice::record_import(voice_id, &voice.workspace, &voice.id).await?;
+        AuditEvent::record(
+            &voice.workspace,
+            &identity.actor(),
+            AuditAction::VoiceImported,
+            format!("voice:{}", voice.id),
+            Some(&request_id.0),
+        )
+        .await?;
+        count += 1;
+        imported.push(voice);
+    }
+    Ok(HttpResponse::Created().json(json!({ "imported": imported, "skipped": skipped })))
+}
+
+pub async fn get_voice_consent(identity: Identity, voice_id: web::Path<String>) -> ApiResponse {
+    identity.authorize(Scope::VoicesRead)?;
+    let voice = Voice::read_in_workspace(&identity.workspace, &voice_id).await?;
+    let Ok(consent) = Consent::read_for_voice(&voice.id).await else {
+        return Err(AppError::not_found("consent_not_found", "no consent found").into());
+    };
+    Ok(HttpResponse::Ok().json(consent))
+}
+
+pub async fn verify_voice_consent(
+    identity: Identity,
+    config: web::Data<Config>,
+    request_id: RequestId,
+    voice_id: web::Path<String>,
+) -> ApiResponse {
+    identity.authorize(Scope::Admin)?;
+    let voice = Voice::read_in_workspace(&identity.workspace, &voice_id).await?;
+    let Ok(consent) = Consent::read_for_voice(&voice.id).await else {
+        return Err(AppError::not_found("consent_not_found", "no consent found").into());
+    };
+    if consent.is_verified() {
+        return Err(AppError::conflict("consent_verified", "consent is already verified").into());
+    }
+    let s3 = Client::new(&config.buckets()?.samples_bucket_name).await;
+    if let Some(recording_key) = &consent.recording_key {
+        if s3.get_object(recording_key.to_string()).await.is_err() {
+            return Err(AppError::conflict(
+                "consent_recording_missing",
+                "consent recording has not been uploaded",
+            )
+            .into());
+        }
+    }
+    let consent = Consent::update(
+        doc! { "_id": &consent.id },
+        doc! {
+            "verified_at": DateTime::now(),
+            "verified_by": identity.actor(),
+        },
+    )
+    .await?;
+    AuditEvent::record(
+        &consent.workspace,
+        &identity.actor(),
+        AuditAction::ConsentVerified,
+        format!("consent:{}", consent.id),
+        Some(&request_id.0),
+    )
+    .await?;
+    // a sample uploaded before verification is left in draft until now
+    let sample_uploaded = s3.get_object(voice.sample_key()).await.is_ok();
+    if voice.status != VoiceStatus::Draft || !sample_uploaded {
+        return Ok(HttpResponse::Ok().json(json!({ "consent": consent, "voice": voice })));
+    }
+    let voice = Voice::update(
+        doc! { "_id": &voice.id },
+        doc! { "status": VoiceStatus::Training.to_string() },
+    )
+    .await?;
+    let sqs = FifoQueue::new(config.queues()?.train_voice_queue_url.to_string()).await;
+    sqs.send_fifo_message(FifoMessage {
+        body: Envelope::new(
+            TrainSampleFifoMessage {
+                voice_id: voice.id.to_string(),
+            },
+            &identity.actor(),
+            Some(&request_id.0),
+        ),
+        group: voice.id.to_string(),
+        deduplication_id: format!("{}-{}", voice.id, voice.updated_at.timestamp_millis()),
+    })
+    .await?;
+    AuditEvent::record(
+        &voice.workspace,
+        &identity.actor(),
+        AuditAction::VoiceTrainingStarted,
+        format!("voice:{}", voice.id),
+        Some(&request_id.0),
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(json!({ "consent": consent, "voice": voice })))
+}
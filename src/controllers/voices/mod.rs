@@ -4,6 +4,18 @@ mod controller;
 
 pub fn router(cfg: &mut ServiceConfig) {
     cfg.route("", web::get().to(controller::list_voices));
+    cfg.route("/import", web::get().to(controller::list_importable_voices));
+    cfg.route("/import", web::post().to(controller::import_voices));
+    cfg.route(
+        "/{id}/consent",
+        web::get().to(controller::get_voice_consent),
+    );
+    cfg.route(
+        "/{id}/consent/verify",
+        web::post().to(controller::verify_voice_consent),
+    );
+    cfg.route("/{id}/restore", web::post().to(controller::restore_voice));
     cfg.route("/{id}", web::get().to(controller::get_voice_by_id));
+    cfg.route("/{id}", web::patch().to(controller::update_voice));
     cfg.route("/{id}", web::delete().to(controller::delete_voice));
 }
@@ -0,0 +1,106 @@
+use lambda_web::actix_web::{web, HttpResponse};
+use mongoose::{
+    bson::{doc, to_bson, Document},
+    Model,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::{ApiResponse, AppError},
+    helpers::{Identity, RequestId},
+    models::{
+        api_key::Scope,
+        audit_event::{AuditAction, AuditEvent},
+        insert,
+        workspace::Workspace,
+    },
+    quota::Limits,
+};
+
+#[derive(Deserialize, Serialize)]
+pub struct CreateWorkspaceBody {
+    pub name: String,
+    pub voice_limit: Option<u64>,
+    #[serde(default)]
+    pub limits: Limits,
+}
+
+pub async fn create_workspace(
+    identity: Identity,
+    request_id: RequestId,
+    body: web::Json<CreateWorkspaceBody>,
+) -> ApiResponse {
+    identity.authorize(Scope::Admin)?;
+    identity.require_root()?;
+    let name = slug::slugify(&body.name);
+    if name.is_empty() {
+        return Err(AppError::validation("workspace_name_empty", "workspace name is empty").into());
+    }
+    if Workspace::read(doc! { "name": &name }).await.is_ok() {
+        return Err(
+            AppError::conflict("workspace_name_taken", "workspace with name is taken").into(),
+        );
+    }
+    let mut workspace = Workspace {
+        name,
+        limits: body.limits.clone(),
+        ..Default::default()
+    };
+    if let Some(voice_limit) = body.voice_limit {
+        workspace.voice_limit = voice_limit;
+    }
+    let workspace = insert(&workspace).await?;
+    AuditEvent::record(
+        &workspace.id,
+        &identity.actor(),
+        AuditAction::WorkspaceCreated,
+        format!("workspace:{}", workspace.id),
+        Some(&request_id.0),
+    )
+    .await?;
+    Ok(HttpResponse::Created().json(workspace))
+}
+
+pub async fn list_workspaces(identity: Identity) -> ApiResponse {
+    identity.authorize(Scope::Admin)?;
+    identity.require_root()?;
+    let workspaces = Workspace::list(None, None).await?;
+    Ok(HttpResponse::Ok().json(workspaces))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct UpdateWorkspaceBody {
+    pub voice_limit: Option<u64>,
+    pub limits: Option<Limits>,
+}
+
+pub async fn update_workspace(
+    identity: Identity,
+    request_id: RequestId,
+    workspace_id: web::Path<String>,
+    body: web::Json<UpdateWorkspaceBody>,
+) -> ApiResponse {
+    identity.authorize(Scope::Admin)?;
+    identity.require_root()?;
+    let workspace = Workspace::read_by_id(&workspace_id).await?;
+    let mut updates = Document::new();
+    if let Some(voice_limit) = body.voice_limit {
+        updates.insert("voice_limit", i64::try_from(voice_limit)?);
+    }
+    if let Some(limits) = &body.limits {
+        updates.insert("limits", to_bson(limits)?);
+    }
+    if updates.is_empty() {
+        return Ok(HttpResponse::Ok().json(workspace));
+    }
+    let workspace = Workspace::update(doc! { "_id": workspace.id }, updates).await?;
+    AuditEvent::record(
+        &workspace.id,
+        &identity.actor(),
+        AuditAction::WorkspaceUpdated,
+        format!("workspace:{}", workspace.id),
+        Some(&request_id.0),
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(workspace))
+}
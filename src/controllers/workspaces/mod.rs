@@ -0,0 +1,9 @@
+use lambda_web::actix_web::web::{self, ServiceConfig};
+
+mod controller;
+
+pub fn router(cfg: &mut ServiceConfig) {
+    cfg.route("", web::post().to(controller::create_workspace));
+    cfg.route("", web::get().to(controller::list_workspaces));
+    cfg.route("/{id}", web::patch().to(controller::update_workspace));
+}
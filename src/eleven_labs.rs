@@ -1,19 +1,77 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
 use anyhow::Result;
 use bytes::Bytes;
 use reqwest::{
-    header::{self, HeaderMap},
+    header::{self, HeaderValue},
     multipart::{self, Form},
-    StatusCode,
+    Client, RequestBuilder, Response, StatusCode,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use crate::env::Config;
+use crate::{
+    env::ElevenLabsSection,
+    retry::{BreakerState, CircuitBreaker, RetryPolicy},
+    secrets::SecretStore,
+};
+
+pub const MODEL_ID: &str = "eleven_monolingual_v1";
+
+// shared across warm invocations so every client sees the provider's recent health
+static BREAKER: Mutex<BreakerState> = Mutex::new(BreakerState::closed());
+
+pub const BASE_URL: &str = "https://api.elevenlabs.io/v1";
+const USER_AGENT: &str = concat!("parrot-api/", env!("CARGO_PKG_VERSION"));
+const API_KEY_HEADER: &str = "xi-api-key";
+
+#[derive(Debug, Clone)]
+pub struct ElevenLabsConfig {
+    pub secrets: Arc<SecretStore>,
+    pub base_url: String,
+    // whole request, synthesis streams can take a while
+    pub timeout: Duration,
+    pub connect_timeout: Duration,
+    pub pool_idle_timeout: Duration,
+    pub retry: RetryPolicy,
+    pub breaker_threshold: u32,
+    pub breaker_cooldown: Duration,
+}
+
+impl ElevenLabsConfig {
+    pub fn new(config: &ElevenLabsSection, secrets: Arc<SecretStore>) -> Self {
+        Self {
+            secrets,
+            base_url: BASE_URL.to_string(),
+            timeout: Duration::from_secs(config.timeout_secs),
+            connect_timeout: Duration::from_secs(config.connect_timeout_secs),
+            pool_idle_timeout: Duration::from_secs(90),
+            retry: RetryPolicy {
+                max_attempts: config.max_attempts.max(1),
+                base_delay: Duration::from_millis(config.retry_base_ms),
+                max_delay: Duration::from_millis(config.retry_max_ms),
+            },
+            breaker_threshold: config.breaker_threshold.max(1),
+            breaker_cooldown: Duration::from_secs(config.breaker_cooldown_secs),
+        }
+    }
+}
 
+// cheap to clone, clones share the same connection pool
+#[derive(Debug, Clone)]
 pub struct ElevenLabs {
-    pub api_key: String,
+    client: Client,
+    secrets: Arc<SecretStore>,
+    base_url: String,
+    pub retry: RetryPolicy,
+    pub breaker: CircuitBreaker,
 }
 
+// eleven labs reports most errors as {"detail": {"status", "message"}}
 #[derive(Debug, Deserialize)]
 pub struct ErrorMessage {
     pub status: String,
@@ -25,6 +83,120 @@ pub struct ErrorResponse {
     pub detail: ErrorMessage,
 }
 
+// request validation errors come back as {"detail": [{"loc", "msg", "type"}]}
+#[derive(Debug, Deserialize)]
+pub struct ValidationMessage {
+    pub msg: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ValidationResponse {
+    pub detail: Vec<ValidationMessage>,
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum ProviderError {
+    #[error("eleven labs rejected the api key: {message}")]
+    Unauthorized { message: String },
+    #[error("eleven labs quota exceeded: {message}")]
+    QuotaExceeded { message: String },
+    #[error("eleven labs voice not found: {message}")]
+    VoiceNotFound { message: String },
+    #[error("eleven labs rejected the request: {message}")]
+    Validation { message: String },
+    #[error("eleven labs rate limited the request: {message}")]
+    RateLimited {
+        message: String,
+        // seconds, from the retry-after header
+        retry_after: Option<u64>,
+    },
+    #[error("eleven labs server error ({status}): {message}")]
+    ServerError { status: u16, message: String },
+    // the request never got a response, or the response could not be read
+    #[error("error connecting to eleven labs: {message}")]
+    Unavailable { message: String },
+    // not attempted, eleven labs has been failing recently
+    #[error("eleven labs circuit is open, retry after {retry_after}s")]
+    CircuitOpen { retry_after: u64 },
+}
+
+impl ProviderError {
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Self::Unauthorized { .. } => Some(401),
+            Self::QuotaExceeded { .. } => None,
+            Self::VoiceNotFound { .. } => Some(404),
+            Self::Validation { .. } => Some(400),
+            Self::RateLimited { .. } => Some(429),
+            Self::ServerError { status, .. } => Some(*status),
+            Self::Unavailable { .. } | Self::CircuitOpen { .. } => None,
+        }
+    }
+
+    // transient failures worth another attempt
+    pub const fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Self::RateLimited { .. } | Self::ServerError { .. } | Self::Unavailable { .. }
+        )
+    }
+
+    // failures that say the provider itself is unhealthy
+    const fn is_outage(&self) -> bool {
+        matches!(self, Self::ServerError { .. } | Self::Unavailable { .. })
+    }
+
+    async fn from_response(response: Response) -> Self {
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get(header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        let body = response.text().await.unwrap_or_default();
+        let (code, message) = match serde_json::from_str::<ErrorResponse>(&body) {
+            Ok(err) => (Some(err.detail.status), err.detail.message),
+            Err(_) => match serde_json::from_str::<ValidationResponse>(&body) {
+                Ok(err) => {
+                    let messages = err.detail.into_iter().map(|detail| detail.msg);
+                    (None, messages.collect::<Vec<_>>().join(", "))
+                }
+                Err(_) => (None, body),
+            },
+        };
+        let err = match (status, code.as_deref()) {
+            // quota errors are sent as a 401
+            (_, Some("quota_exceeded")) => Self::QuotaExceeded { message },
+            (_, Some("voice_not_found")) | (StatusCode::NOT_FOUND, _) => {
+                Self::VoiceNotFound { message }
+            }
+            (StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN, _) => Self::Unauthorized { message },
+            (StatusCode::TOO_MANY_REQUESTS, _) => Self::RateLimited {
+                message,
+                retry_after,
+            },
+            (status, _) if status.is_server_error() => Self::ServerError {
+                status: status.as_u16(),
+                message,
+            },
+            _ => Self::Validation { message },
+        };
+        tracing::error!("{:?}", err);
+        err
+    }
+}
+
+impl From<reqwest::Error> for ProviderError {
+    fn from(err: reqwest::Error) -> Self {
+        tracing::error!("error connecting to server: {:?}", err.to_string());
+        Self::Unavailable {
+            message: err.to_string(),
+        }
+    }
+}
+
+type ProviderResult<T> = Result<T, ProviderError>;
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Voice {
     pub voice_id: String,
@@ -43,103 +215,200 @@ pub struct VoicesResponse {
     pub voices: Vec<Voice>,
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Subscription {
+    pub tier: String,
+    pub character_count: u64,
+    pub character_limit: u64,
+    pub next_character_count_reset_unix: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct VoiceSettings {
+    pub stability: f64,
+    pub similarity_boost: f64,
+    pub style: f64,
+    pub use_speaker_boost: bool,
+}
+
+impl Default for VoiceSettings {
+    fn default() -> Self {
+        Self {
+            stability: 0.0,
+            similarity_boost: 0.0,
+            style: 0.5,
+            use_speaker_boost: true,
+        }
+    }
+}
+
+impl VoiceSettings {
+    // eleven labs only accepts ratios between 0 and 1
+    pub fn out_of_range(&self) -> Vec<&'static str> {
+        [
+            ("stability", self.stability),
+            ("similarity_boost", self.similarity_boost),
+            ("style", self.style),
+        ]
+        .into_iter()
+        .filter(|(_, value)| !(0.0..=1.0).contains(value))
+        .map(|(field, _)| field)
+        .collect()
+    }
+}
+
+// a key that is not a valid header value fails the request once it is sent
+fn with_api_key(request: RequestBuilder, key: &str) -> RequestBuilder {
+    match HeaderValue::from_str(key) {
+        Ok(mut value) => {
+            value.set_sensitive(true);
+            request.header(API_KEY_HEADER, value)
+        }
+        Err(_) => request.header(API_KEY_HEADER, key),
+    }
+}
+
 impl ElevenLabs {
     // Internal Methods
-    fn headers(&self) -> Result<HeaderMap> {
-        let mut headers = header::HeaderMap::new();
-        let key = &self.api_key;
-        headers.insert("xi-api-key", key.parse()?);
-        Ok(headers)
-    }
-    fn base_url() -> String {
-        "https://api.elevenlabs.io/v1".to_string()
-    }
-    async fn get<T: for<'a> Deserialize<'a> + Serialize>(&self, url: &str) -> Result<T> {
-        let base_url = Self::base_url();
-        let headers = self.headers()?;
-        let client = reqwest::Client::builder();
-        let client = client.default_headers(headers).build()?;
-        let url = format!("{base_url}/{url}");
-        let response = match client.get(url).send().await {
-            Ok(response) => response,
-            Err(err) => {
-                tracing::error!("error connecting to server: {:?}", err.to_string());
-                anyhow::bail!(err)
-            }
+    fn url(&self, path: &str) -> String {
+        format!("{}/{path}", self.base_url)
+    }
+    // every error status is turned into a provider error before the body is read
+    async fn send(&self, request: RequestBuilder) -> ProviderResult<Response> {
+        if let Some(open_for) = self.breaker.open_for() {
+            return Err(ProviderError::CircuitOpen {
+                retry_after: open_for.as_secs() + 1,
+            });
+        }
+        let result = match self.send_authorized(request).await {
+            Ok(response) if response.status().is_success() => Ok(response),
+            Ok(response) => Err(ProviderError::from_response(response).await),
+            Err(err) => Err(err),
         };
-        let data = response.json::<serde_json::Value>().await?;
-        let serialized = serde_json::to_string(&data)?;
-        match serde_json::from_str::<ErrorResponse>(&serialized) {
-            Ok(err) => {
-                tracing::error!("{:?}", err);
-                anyhow::bail!("{:?}", err.detail.message)
+        match &result {
+            Err(err) if err.is_outage() => self.breaker.record_failure(),
+            _ => self.breaker.record_success(),
+        }
+        result
+    }
+    // the first key is current, the others are only tried while a rotation is in progress
+    async fn send_authorized(&self, request: RequestBuilder) -> ProviderResult<Response> {
+        let keys = self.secrets.eleven_labs_api_keys();
+        let Some((last, rotating)) = keys.split_last() else {
+            return Ok(request.send().await?);
+        };
+        for key in rotating {
+            // bodies that cannot be cloned only ever get the current key
+            let Some(attempt) = request.try_clone() else {
+                return Ok(with_api_key(request, key).send().await?);
+            };
+            let response = with_api_key(attempt, key).send().await?;
+            if response.status() != StatusCode::UNAUTHORIZED {
+                return Ok(response);
+            }
+            // quota errors are a 401 too, another key would not get past them
+            match ProviderError::from_response(response).await {
+                ProviderError::Unauthorized { .. } => {
+                    tracing::warn!("eleven labs rejected an api key, trying the next one");
+                }
+                err => return Err(err),
             }
-            Err(_) => Ok(serde_json::from_str::<T>(&serialized)?),
         }
+        Ok(with_api_key(request, last).send().await?)
+    }
+    // only for idempotent requests, multipart bodies cannot be cloned and are sent once
+    async fn send_with_retry(&self, request: RequestBuilder) -> ProviderResult<Response> {
+        let mut attempt = 0;
+        loop {
+            let Some(attempt_request) = request.try_clone() else {
+                return self.send(request).await;
+            };
+            let err = match self.send(attempt_request).await {
+                Ok(response) => return Ok(response),
+                Err(err) if err.is_retryable() => err,
+                Err(err) => return Err(err),
+            };
+            let retry_after = match &err {
+                ProviderError::RateLimited { retry_after, .. } => {
+                    retry_after.map(Duration::from_secs)
+                }
+                _ => None,
+            };
+            let Some(delay) = self.retry.delay(attempt, retry_after) else {
+                return Err(err);
+            };
+            attempt += 1;
+            tracing::warn!("retrying eleven labs request in {delay:?}, attempt {attempt}: {err}");
+            tokio::time::sleep(delay).await;
+        }
+    }
+    // open while eleven labs is failing, so workers can defer instead of calling it
+    pub fn circuit_open(&self) -> Option<Duration> {
+        self.breaker.open_for()
+    }
+    async fn json<T: for<'a> Deserialize<'a>>(response: Response) -> ProviderResult<T> {
+        let body = response.bytes().await?;
+        serde_json::from_slice::<T>(&body).map_err(|err| ProviderError::Unavailable {
+            message: format!("unexpected response body: {err}"),
+        })
+    }
+    async fn get<T: for<'a> Deserialize<'a>>(&self, url: &str) -> ProviderResult<T> {
+        let response = self.send_with_retry(self.client.get(self.url(url))).await?;
+        Self::json::<T>(response).await
     }
 
-    async fn post_form<T: for<'a> Deserialize<'a> + Serialize>(
+    async fn post_form<T: for<'a> Deserialize<'a>>(
         &self,
         url: &str,
         form: Form,
-    ) -> Result<T> {
-        let base_url = Self::base_url();
-        let mut headers = self.headers()?;
-        headers.insert("Content-Type", "multipart/form-data".parse()?);
-        let client = reqwest::Client::builder();
-        let client = client.default_headers(headers).build()?;
-        let url = format!("{base_url}/{url}");
-        let response = match client.post(url).multipart(form).send().await {
-            Ok(response) => response,
-            Err(err) => {
-                tracing::error!("error connecting to server: {:?}", err.to_string());
-                anyhow::bail!(err)
-            }
-        };
-        let data = response.json::<serde_json::Value>().await?;
-        let serialized = serde_json::to_string(&data)?;
-        match serde_json::from_str::<ErrorResponse>(&serialized) {
-            Ok(err) => {
-                tracing::error!("{:?}", err);
-                anyhow::bail!("{:?}", err.detail.message)
-            }
-            Err(_) => Ok(serde_json::from_str::<T>(&serialized)?),
-        }
+    ) -> ProviderResult<T> {
+        let request = self.client.post(self.url(url)).multipart(form);
+        let response = self.send(request).await?;
+        Self::json::<T>(response).await
     }
 
     // Public Api Methods
-    pub fn new() -> Result<Self> {
-        let Config {
-            eleven_labs_api_key: api_key,
-            ..
-        } = Config::new()?;
-        Ok(Self { api_key })
+    // build once per process and share it, every call reuses the pooled connections
+    pub fn new(config: &ElevenLabsConfig) -> Result<Self> {
+        let client = Client::builder()
+            .user_agent(USER_AGENT)
+            .timeout(config.timeout)
+            .connect_timeout(config.connect_timeout)
+            .pool_idle_timeout(config.pool_idle_timeout)
+            .tcp_keepalive(Duration::from_secs(60))
+            .build()?;
+        Ok(Self {
+            client,
+            secrets: config.secrets.clone(),
+            base_url: config.base_url.to_string(),
+            retry: config.retry.clone(),
+            breaker: CircuitBreaker::new(
+                &BREAKER,
+                config.breaker_threshold,
+                config.breaker_cooldown,
+            ),
+        })
     }
 
-    pub async fn get_voices(&self) -> Result<Vec<Voice>> {
+    pub async fn get_voices(&self) -> ProviderResult<Vec<Voice>> {
         let response = self.get::<VoicesResponse>("voices").await?;
         Ok(response.voices)
     }
 
-    pub async fn get_voice(&self, voice_id: &str) -> Result<Voice> {
+    pub async fn get_subscription(&self) -> ProviderResult<Subscription> {
+        let response = self.get::<Subscription>("user/subscription").await?;
+        Ok(response)
+    }
+
+    pub async fn get_voice(&self, voice_id: &str) -> ProviderResult<Voice> {
         let response = self.get::<Voice>(&format!("voices/{voice_id}")).await?;
         Ok(response)
     }
 
-    pub async fn delete_voice(&self, voice_id: &str) -> Result<StatusCode> {
-        let base_url = Self::base_url();
-        let headers = self.headers()?;
-        let client = reqwest::Client::builder();
-        let client = client.default_headers(headers).build()?;
-        let url = format!("{base_url}/voices/{voice_id}");
-        let response = match client.delete(url).send().await {
-            Ok(response) => response,
-            Err(err) => {
-                tracing::error!("error connecting to server: {:?}", err.to_string());
-                anyhow::bail!(err)
-            }
-        };
-        Ok(response.status())
+    pub async fn delete_voice(&self, voice_id: &str) -> ProviderResult<()> {
+        let url = self.url(&format!("voices/{voice_id}"));
+        self.send_with_retry(self.client.delete(url)).await?;
+        Ok(())
     }
 
     // 11mb max file size
@@ -148,7 +417,7 @@ impl ElevenLabs {
         voice_name: &str,
         data: &Vec<u8>,
         description: Option<&str>,
-    ) -> Result<AddVoiceResponse> {
+    ) -> ProviderResult<AddVoiceResponse> {
         let file_name = slug::slugify(voice_name);
         let part = multipart::Part::stream(data.to_owned())
             .file_name(format!("{file_name}.mp3"))
@@ -166,24 +435,63 @@ impl ElevenLabs {
         Ok(response)
     }
 
-    pub async fn text_to_speech(&self, voice_id: &str, text: &str) -> Result<Bytes> {
-        let base_url = Self::base_url();
-        let headers = self.headers()?;
+    pub async fn edit_voice(
+        &self,
+        voice_id: &str,
+        voice_name: &str,
+        description: Option<&str>,
+        labels: Option<&HashMap<String, String>>,
+    ) -> ProviderResult<()> {
+        let mut form = multipart::Form::new()
+            .text("name", voice_name.to_string())
+            .text(
+                "description",
+                description.map_or(String::new(), std::string::ToString::to_string),
+            );
+        if let Some(labels) = labels {
+            let labels =
+                serde_json::to_string(labels).map_err(|err| ProviderError::Validation {
+                    message: err.to_string(),
+                })?;
+            form = form.text("labels", labels);
+        }
+        self.post_form::<serde_json::Value>(&format!("voices/{voice_id}/edit"), form)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn text_to_speech(
+        &self,
+        voice_id: &str,
+        text: &str,
+        settings: &VoiceSettings,
+    ) -> ProviderResult<Bytes> {
         let optimizations = "optimize_streaming_latency=3";
-        let url = format!("{base_url}/text-to-speech/{voice_id}/stream?{optimizations}");
-        let client = reqwest::Client::builder();
-        let client = client.default_headers(headers).build()?;
+        let url = self.url(&format!("text-to-speech/{voice_id}/stream?{optimizations}"));
         let payload = json!({
             "text": text,
-            "model_id": "eleven_monolingual_v1",
-            "voice_settings": {
-              "stability": 0,
-              "similarity_boost": 0,
-              "style": 0.5,
-              "use_speaker_boost": true
-            }
+            "model_id": MODEL_ID,
+            "voice_settings": settings
         });
-        let response = client.post(url).json(&payload).send().await?;
+        // synthesis has no side effects upstream, so it is safe to retry
+        let response = self
+            .send_with_retry(self.client.post(url).json(&payload))
+            .await?;
+        // a success without audio is a fault upstream, not a bad request, so it is retried
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        if !content_type.starts_with("audio/") {
+            let body = response.text().await.unwrap_or_default();
+            let err = ProviderError::Unavailable {
+                message: format!("expected audio, got {content_type:?}: {body}"),
+            };
+            tracing::error!("{:?}", err);
+            return Err(err);
+        }
         Ok(response.bytes().await?)
     }
 }
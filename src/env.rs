@@ -0,0 +1,352 @@
+use std::{collections::HashMap, fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::Level;
+
+// read in debug builds when CONFIG_FILE is not set, for local runs
+const LOCAL_CONFIG_FILE: &str = "config.json";
+// the poll loop extends visibility at half the timeout, so it needs room for the sqs calls
+const MIN_VISIBILITY_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum Stage {
+    Local,
+    Test,
+    Prod,
+    Other(String),
+}
+
+// the parts of the config only some binaries need, everything else always loads with defaults
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    ElevenLabs,
+    Queues,
+    Buckets,
+}
+
+impl Display for Section {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Section::ElevenLabs => write!(f, "eleven labs"),
+            Section::Queues => write!(f, "queues"),
+            Section::Buckets => write!(f, "buckets"),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("invalid configuration: {}", problems(.missing, .invalid))]
+    Invalid {
+        missing: Vec<&'static str>,
+        invalid: Vec<String>,
+    },
+    #[error("error reading config file {path}: {error}")]
+    File { path: String, error: String },
+    #[error("{0} configuration was not loaded")]
+    NotLoaded(Section),
+}
+
+fn problems(missing: &[&'static str], invalid: &[String]) -> String {
+    let mut problems = vec![];
+    if !missing.is_empty() {
+        problems.push(format!("missing {}", missing.join(", ")));
+    }
+    if !invalid.is_empty() {
+        problems.push(format!("invalid {}", invalid.join(", ")));
+    }
+    problems.join("; ")
+}
+
+// env vars first, then the config file, collecting every problem instead of stopping at the first
+struct Source {
+    file: HashMap<String, String>,
+    missing: Vec<&'static str>,
+    invalid: Vec<String>,
+}
+
+impl Source {
+    fn load() -> Result<Self, ConfigError> {
+        let path = match std::env::var("CONFIG_FILE") {
+            Ok(path) => Some(path),
+            Err(_) if cfg!(debug_assertions) => std::path::Path::new(LOCAL_CONFIG_FILE)
+                .exists()
+                .then(|| LOCAL_CONFIG_FILE.to_string()),
+            Err(_) => None,
+        };
+        let file = match path {
+            Some(path) => std::fs::read_to_string(&path)
+                .map_err(|err| err.to_string())
+                .and_then(|raw| {
+                    serde_json::from_str::<HashMap<String, String>>(&raw)
+                        .map_err(|err| err.to_string())
+                })
+                .map_err(|error| ConfigError::File { path, error })?,
+            None => HashMap::new(),
+        };
+        Ok(Self {
+            file,
+            missing: vec![],
+            invalid: vec![],
+        })
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        std::env::var(name)
+            .ok()
+            .or_else(|| self.file.get(name).cloned())
+            .filter(|value| !value.is_empty())
+    }
+
+    fn required(&mut self, name: &'static str) -> String {
+        self.get(name).unwrap_or_else(|| {
+            self.missing.push(name);
+            String::default()
+        })
+    }
+
+    fn parse_or<T: FromStr>(&mut self, name: &str, default: T) -> T {
+        let Some(value) = self.get(name) else {
+            return default;
+        };
+        value.parse().unwrap_or_else(|_| {
+            self.invalid.push(format!("{name}={value}"));
+            default
+        })
+    }
+
+    fn parse_at_least<T: FromStr + PartialOrd + Display>(
+        &mut self,
+        name: &str,
+        default: T,
+        min: T,
+    ) -> T {
+        let value = self.parse_or(name, default);
+        if value < min {
+            self.invalid
+                .push(format!("{name}={value} (at least {min})"));
+        }
+        value
+    }
+
+    // comma separated, so several values can be valid during a rotation
+    fn list(&self, name: &str) -> Vec<String> {
+        self.get(name).map_or_else(Vec::new, |value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(ToString::to_string)
+                .collect()
+        })
+    }
+
+    // for clients that only read env vars, a value from the config file would be ignored
+    fn required_env(&mut self, name: &'static str) -> String {
+        let value = self.required(name);
+        if !value.is_empty() && std::env::var_os(name).is_none() {
+            self.invalid.push(format!(
+                "{name} (must be an env var, not in the config file)"
+            ));
+        }
+        value
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.get(name)
+            .is_some_and(|value| value.eq_ignore_ascii_case("true"))
+    }
+}
+
+#[derive(Debug)]
+pub struct AuthSection {
+    pub jwks_url: Option<String>,
+    pub jwks_file: Option<String>,
+    pub jwt_issuer: Option<String>,
+    pub jwt_audience: Option<String>,
+    pub jwt_workspace_claim: String,
+    pub jwt_scopes_claim: String,
+}
+
+// values from env vars are fixed, the files are reloaded every `reload_secs`
+#[derive(Debug, Clone)]
+pub struct SecretsSection {
+    pub authentication_tokens: Vec<String>,
+    pub authentication_tokens_file: Option<String>,
+    pub eleven_labs_api_keys: Vec<String>,
+    pub eleven_labs_api_keys_file: Option<String>,
+    // json with authentication_tokens and eleven_labs_api_keys lists
+    pub secrets_file: Option<String>,
+    pub reload_secs: u64,
+}
+
+#[derive(Debug)]
+pub struct RetentionSection {
+    pub voice_retention_days: i64,
+    pub purge_voice_outputs: bool,
+    pub reconcile_repair: bool,
+    // clones younger than this may not be linked to their voice yet
+    pub reconcile_grace_mins: i64,
+}
+
+#[derive(Debug)]
+pub struct WorkerSection {
+    pub concurrency: usize,
+    pub visibility_timeout_secs: u64,
+    // the queues' redrive policy, the last receive that fails is dead lettered by sqs
+    pub max_receive_count: u32,
+}
+
+#[derive(Debug)]
+pub struct ElevenLabsSection {
+    pub timeout_secs: u64,
+    pub connect_timeout_secs: u64,
+    pub max_attempts: u32,
+    pub retry_base_ms: u64,
+    pub retry_max_ms: u64,
+    pub breaker_threshold: u32,
+    pub breaker_cooldown_secs: u64,
+}
+
+#[derive(Debug)]
+pub struct QueueSection {
+    pub create_output_queue_url: String,
+    pub train_voice_queue_url: String,
+    pub create_output_dead_letter_queue_url: Option<String>,
+    pub train_voice_dead_letter_queue_url: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct BucketSection {
+    pub samples_bucket_name: String,
+    pub outputs_bucket_name: String,
+}
+
+// loaded once per binary and shared, sections a binary does not ask for are never read
+#[derive(Debug)]
+pub struct Config {
+    pub stage: Stage,
+    pub log_level: Level,
+    // mongoose connects with the MONGO_URI env var, this is only validated here
+    pub mongo_uri: String,
+    pub auth: AuthSection,
+    pub secrets: SecretsSection,
+    pub retention: RetentionSection,
+    pub worker: WorkerSection,
+    eleven_labs: Option<ElevenLabsSection>,
+    queues: Option<QueueSection>,
+    buckets: Option<BucketSection>,
+}
+
+impl Config {
+    pub fn load(sections: &[Section]) -> Result<Self, ConfigError> {
+        if cfg!(debug_assertions) {
+            use dotenv::dotenv;
+            dotenv().ok();
+        }
+        let mut source = Source::load()?;
+        let secrets = SecretsSection {
+            authentication_tokens: source.list("AUTHENTICATION_TOKEN"),
+            authentication_tokens_file: source.get("AUTHENTICATION_TOKEN_FILE"),
+            eleven_labs_api_keys: source.list("ELEVEN_LABS_API_KEY"),
+            eleven_labs_api_keys_file: source.get("ELEVEN_LABS_API_KEY_FILE"),
+            secrets_file: source.get("SECRETS_FILE"),
+            reload_secs: source.parse_or("SECRETS_RELOAD_SECS", 60),
+        };
+        let has_eleven_labs_key = !secrets.eleven_labs_api_keys.is_empty()
+            || secrets.eleven_labs_api_keys_file.is_some()
+            || secrets.secrets_file.is_some();
+        if sections.contains(&Section::ElevenLabs) && !has_eleven_labs_key {
+            source.missing.push("ELEVEN_LABS_API_KEY");
+        }
+        let config = Self {
+            log_level: source.parse_or("LOG_LEVEL", Level::ERROR),
+            stage: match source.get("STAGE").map(|stage| stage.to_uppercase()) {
+                None => Stage::Local,
+                Some(stage) => match stage.as_str() {
+                    "LOCAL" => Stage::Local,
+                    "PROD" => Stage::Prod,
+                    "TEST" => Stage::Test,
+                    _ => Stage::Other(stage),
+                },
+            },
+            mongo_uri: source.required_env("MONGO_URI"),
+            auth: AuthSection {
+                jwks_url: source.get("JWKS_URL"),
+                jwks_file: source.get("JWKS_FILE"),
+                jwt_issuer: source.get("JWT_ISSUER"),
+                jwt_audience: source.get("JWT_AUDIENCE"),
+                jwt_workspace_claim: source
+                    .get("JWT_WORKSPACE_CLAIM")
+                    .unwrap_or_else(|| "workspace".to_string()),
+                jwt_scopes_claim: source
+                    .get("JWT_SCOPES_CLAIM")
+                    .unwrap_or_else(|| "scope".to_string()),
+            },
+            secrets,
+            retention: RetentionSection {
+                voice_retention_days: source.parse_at_least("VOICE_RETENTION_DAYS", 30, 1),
+                purge_voice_outputs: source.flag("PURGE_VOICE_OUTPUTS"),
+                reconcile_repair: source.flag("RECONCILE_REPAIR"),
+                reconcile_grace_mins: source.parse_or("RECONCILE_GRACE_MINS", 60),
+            },
+            worker: WorkerSection {
+                concurrency: source.parse_or("WORKER_CONCURRENCY", 4),
+                visibility_timeout_secs: source.parse_at_least(
+                    "WORKER_VISIBILITY_TIMEOUT_SECS",
+                    120,
+                    MIN_VISIBILITY_TIMEOUT_SECS,
+                ),
+                max_receive_count: source.parse_or("WORKER_MAX_RECEIVE_COUNT", 5),
+            },
+            eleven_labs: sections
+                .contains(&Section::ElevenLabs)
+                .then(|| ElevenLabsSection {
+                    timeout_secs: source.parse_or("ELEVEN_LABS_TIMEOUT_SECS", 60),
+                    connect_timeout_secs: source.parse_or("ELEVEN_LABS_CONNECT_TIMEOUT_SECS", 5),
+                    max_attempts: source.parse_or("ELEVEN_LABS_MAX_ATTEMPTS", 3),
+                    retry_base_ms: source.parse_or("ELEVEN_LABS_RETRY_BASE_MS", 250),
+                    retry_max_ms: source.parse_or("ELEVEN_LABS_RETRY_MAX_MS", 5_000),
+                    breaker_threshold: source.parse_or("ELEVEN_LABS_BREAKER_THRESHOLD", 5),
+                    breaker_cooldown_secs: source.parse_or("ELEVEN_LABS_BREAKER_COOLDOWN_SECS", 30),
+                }),
+            queues: sections.contains(&Section::Queues).then(|| QueueSection {
+                create_output_queue_url: source.required("CREATE_OUTPUT_QUEUE_URL"),
+                train_voice_queue_url: source.required("TRAIN_VOICE_QUEUE_URL"),
+                create_output_dead_letter_queue_url: source
+                    .get("CREATE_OUTPUT_DEAD_LETTER_QUEUE_URL"),
+                train_voice_dead_letter_queue_url: source.get("TRAIN_VOICE_DEAD_LETTER_QUEUE_URL"),
+            }),
+            buckets: sections.contains(&Section::Buckets).then(|| BucketSection {
+                samples_bucket_name: source.required("SAMPLES_BUCKET_NAME"),
+                outputs_bucket_name: source.required("OUTPUTS_BUCKET_NAME"),
+            }),
+        };
+        if !source.missing.is_empty() || !source.invalid.is_empty() {
+            return Err(ConfigError::Invalid {
+                missing: source.missing,
+                invalid: source.invalid,
+            });
+        }
+        Ok(config)
+    }
+
+    pub fn eleven_labs(&self) -> Result<&ElevenLabsSection, ConfigError> {
+        self.eleven_labs
+            .as_ref()
+            .ok_or(ConfigError::NotLoaded(Section::ElevenLabs))
+    }
+
+    pub fn queues(&self) -> Result<&QueueSection, ConfigError> {
+        self.queues
+            .as_ref()
+            .ok_or(ConfigError::NotLoaded(Section::Queues))
+    }
+
+    pub fn buckets(&self) -> Result<&BucketSection, ConfigError> {
+        self.buckets
+            .as_ref()
+            .ok_or(ConfigError::NotLoaded(Section::Buckets))
+    }
+}
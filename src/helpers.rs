@@ -1,9 +1,178 @@
-use lambda_web::actix_web::HttpRequest;
+use chrono::Utc;
+use futures::future::{ready, LocalBoxFuture, Ready};
+use lambda_web::actix_web::{dev::Payload, web, FromRequest, HttpMessage, HttpRequest};
+use mongoose::{
+    bson::{doc, DateTime},
+    Model,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use serde_json::json;
 
-use crate::env;
+use crate::{
+    env::Config,
+    errors::AppError,
+    jwt,
+    models::{
+        api_key::{ApiKey, Scope},
+        workspace::{Workspace, DEFAULT_WORKSPACE},
+    },
+    quota::{self, QuotaSubject},
+    secrets::SecretStore,
+};
 
-pub async fn authenticate(req: HttpRequest) -> anyhow::Result<()> {
-    let config = env::Config::new()?;
+// lets the bootstrap token act on a workspace other than the default one
+const WORKSPACE_HEADER: &str = "X-Workspace-Id";
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+// rfc 3339 timestamps from query strings and bodies
+pub fn parse_date(value: &str) -> Result<DateTime, AppError> {
+    match chrono::DateTime::parse_from_rfc3339(value) {
+        Ok(date) => Ok(DateTime::from_chrono(date.with_timezone(&Utc))),
+        Err(err) => Err(AppError::Validation {
+            code: "invalid_timestamp",
+            error: format!("{value} is not a valid rfc 3339 timestamp"),
+            details: Some(json!({ "reason": err.to_string() })),
+        }),
+    }
+}
+
+pub fn generate_request_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(20)
+        .map(char::from)
+        .collect()
+}
+
+// the caller's request id when given, so logs and audit events can be correlated
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub fn of(req: &HttpRequest) -> Self {
+        if let Some(request_id) = req.extensions().get::<Self>() {
+            return request_id.clone();
+        }
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| !value.is_empty())
+            .map_or_else(generate_request_id, ToString::to_string);
+        let request_id = Self(request_id);
+        req.extensions_mut().insert(request_id.clone());
+        request_id
+    }
+}
+
+impl FromRequest for RequestId {
+    type Error = AppError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(Self::of(req)))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Identity {
+    // set when authenticated with an api key
+    pub api_key: Option<String>,
+    // set when authenticated with an identity provider jwt
+    pub user_id: Option<String>,
+    pub workspace: String,
+    pub scopes: Vec<Scope>,
+    // every key and workspace whose limits apply to this identity
+    pub quotas: Vec<QuotaSubject>,
+}
+
+impl Identity {
+    // the bootstrap token is the only identity with neither a key nor a user
+    pub const fn is_root(&self) -> bool {
+        self.api_key.is_none() && self.user_id.is_none()
+    }
+
+    // recorded on documents and usage so work can be traced back to who asked for it
+    pub fn actor(&self) -> String {
+        match (&self.api_key, &self.user_id) {
+            (Some(api_key), _) => format!("key:{api_key}"),
+            (None, Some(user_id)) => format!("user:{user_id}"),
+            (None, None) => "root".to_string(),
+        }
+    }
+
+    pub fn authorize(&self, scope: Scope) -> Result<(), AppError> {
+        if self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope) {
+            return Ok(());
+        }
+        Err(AppError::Forbidden {
+            error: format!("authentication is missing the {scope} scope"),
+        })
+    }
+
+    pub fn require_root(&self) -> Result<(), AppError> {
+        if self.is_root() {
+            return Ok(());
+        }
+        Err(AppError::Forbidden {
+            error: "root token required".to_string(),
+        })
+    }
+}
+
+impl FromRequest for Identity {
+    type Error = AppError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            if let Some(identity) = req.extensions().get::<Self>() {
+                return Ok(identity.clone());
+            }
+            let identity = authenticate(&req)
+                .await
+                .map_err(|err| AppError::Unauthorized {
+                    error: err.to_string(),
+                })?;
+            let consumed = quota::consume_request(&identity.quotas)
+                .await
+                .map_err(|err| AppError::from(&err))?;
+            if let Err(quota) = consumed {
+                return Err(AppError::TooManyRequests {
+                    error: format!("{} request rate limit reached", quota.subject),
+                    limit: quota.limit,
+                    retry_after: quota.reset_after,
+                });
+            }
+            req.extensions_mut().insert(identity.clone());
+            Ok(identity)
+        })
+    }
+}
+
+async fn read_workspace(workspace: &str) -> anyhow::Result<Workspace> {
+    let Ok(workspace) = Workspace::read_by_id(workspace).await else {
+        anyhow::bail!("workspace {workspace} does not exist")
+    };
+    Ok(workspace)
+}
+
+fn workspace_quota(workspace: Workspace) -> QuotaSubject {
+    QuotaSubject {
+        subject: format!("workspace:{}", workspace.id),
+        limits: workspace.limits,
+    }
+}
+
+pub async fn authenticate(req: &HttpRequest) -> anyhow::Result<Identity> {
+    let Some(config) = req.app_data::<web::Data<Config>>() else {
+        anyhow::bail!("config is not shared with the app")
+    };
+    let Some(secrets) = req.app_data::<web::Data<SecretStore>>() else {
+        anyhow::bail!("secrets are not shared with the app")
+    };
+    let config = &config.auth;
     let bearer_token = match req.headers().get("Authorization") {
         Some(value) => value.to_str(),
         None => anyhow::bail!("missing authentication header"),
@@ -13,8 +182,55 @@ pub async fn authenticate(req: HttpRequest) -> anyhow::Result<()> {
         Some(token) => *token,
         None => anyhow::bail!("missing authentication token"),
     };
-    if token != config.authentication_token {
+    // the shared token is only kept to bootstrap the first admin keys
+    if secrets.is_authentication_token(token) {
+        let workspace = match req.headers().get(WORKSPACE_HEADER) {
+            Some(value) => value.to_str()?.to_string(),
+            None => DEFAULT_WORKSPACE.to_string(),
+        };
+        let workspace = read_workspace(&workspace).await?;
+        return Ok(Identity {
+            api_key: None,
+            user_id: None,
+            workspace: workspace.id.to_string(),
+            scopes: vec![Scope::Admin],
+            quotas: vec![workspace_quota(workspace)],
+        });
+    }
+    if jwt::looks_like_jwt(token) {
+        let claims = jwt::verify(config, token).await?;
+        let workspace = read_workspace(&claims.workspace).await?;
+        return Ok(Identity {
+            api_key: None,
+            user_id: Some(claims.user_id),
+            workspace: workspace.id.to_string(),
+            scopes: claims.scopes,
+            quotas: vec![workspace_quota(workspace)],
+        });
+    }
+    let Ok(api_key) = ApiKey::find_by_secret(token).await else {
         anyhow::bail!("invalid authentication token")
+    };
+    if api_key.is_expired() {
+        anyhow::bail!("authentication token has expired")
     }
-    Ok(())
+    ApiKey::update(
+        doc! { "_id": &api_key.id },
+        doc! { "last_used_at": DateTime::now() },
+    )
+    .await?;
+    let workspace = read_workspace(&api_key.workspace).await?;
+    Ok(Identity {
+        quotas: vec![
+            QuotaSubject {
+                subject: format!("key:{}", api_key.id),
+                limits: api_key.limits,
+            },
+            workspace_quota(workspace),
+        ],
+        api_key: Some(api_key.id),
+        user_id: None,
+        workspace: api_key.workspace,
+        scopes: api_key.scopes,
+    })
 }
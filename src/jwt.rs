@@ -0,0 +1,147 @@
+use std::{
+    sync::{OnceLock, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde_json::Value;
+
+use crate::{env::AuthSection, models::api_key::Scope};
+
+const JWKS_TTL: Duration = Duration::from_secs(10 * 60);
+// unknown key ids can be sent by anyone, so they only refetch the key set this often
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+const JWKS_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct CachedJwks {
+    keys: JwkSet,
+    fetched_at: Instant,
+    // failed fetches count too, so an unreachable endpoint is not retried per request
+    attempted_at: Instant,
+}
+
+// shared across warm invocations so the key set is not fetched per request
+static JWKS: RwLock<Option<CachedJwks>> = RwLock::new(None);
+static JWKS_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+#[derive(Debug)]
+pub struct Claims {
+    pub user_id: String,
+    pub workspace: String,
+    pub scopes: Vec<Scope>,
+}
+
+pub fn looks_like_jwt(token: &str) -> bool {
+    token.split('.').count() == 3
+}
+
+async fn fetch_jwks(config: &AuthSection) -> Result<JwkSet> {
+    if let Some(url) = &config.jwks_url {
+        let client = match JWKS_CLIENT.get() {
+            Some(client) => client,
+            None => {
+                let client = reqwest::Client::builder().timeout(JWKS_TIMEOUT).build()?;
+                JWKS_CLIENT.get_or_init(|| client)
+            }
+        };
+        let response = client.get(url).send().await?.error_for_status()?;
+        return Ok(response.json::<JwkSet>().await?);
+    }
+    if let Some(path) = &config.jwks_file {
+        let raw = std::fs::read_to_string(path)?;
+        return Ok(serde_json::from_str::<JwkSet>(&raw)?);
+    }
+    anyhow::bail!("jwt authentication is not configured")
+}
+
+fn cached_jwks(refresh: bool) -> Option<JwkSet> {
+    let cache = JWKS.read().ok()?;
+    cache
+        .as_ref()
+        .filter(|cached| {
+            cached.attempted_at.elapsed() < JWKS_REFRESH_INTERVAL
+                || (!refresh && cached.fetched_at.elapsed() < JWKS_TTL)
+        })
+        .map(|cached| cached.keys.clone())
+}
+
+async fn jwks(config: &AuthSection, refresh: bool) -> Result<JwkSet> {
+    if let Some(keys) = cached_jwks(refresh) {
+        return Ok(keys);
+    }
+    let fetched = fetch_jwks(config).await;
+    if let Ok(mut cache) = JWKS.write() {
+        match (&fetched, cache.as_mut()) {
+            (Ok(keys), _) => {
+                *cache = Some(CachedJwks {
+                    keys: keys.clone(),
+                    fetched_at: Instant::now(),
+                    attempted_at: Instant::now(),
+                });
+            }
+            (Err(_), Some(cached)) => cached.attempted_at = Instant::now(),
+            (Err(_), None) => (),
+        }
+    }
+    fetched
+}
+
+// scopes may be a space delimited string (oauth style) or an array of strings
+fn scopes_from_claim(claim: Option<&Value>) -> Vec<Scope> {
+    let scopes = match claim {
+        Some(Value::String(scopes)) => scopes.split(' ').map(str::to_string).collect(),
+        Some(Value::Array(scopes)) => scopes
+            .iter()
+            .filter_map(Value::as_str)
+            .map(str::to_string)
+            .collect(),
+        _ => vec![],
+    };
+    scopes
+        .iter()
+        .filter_map(|scope| scope.parse::<Scope>().ok())
+        .collect()
+}
+
+pub async fn verify(config: &AuthSection, token: &str) -> Result<Claims> {
+    let header = decode_header(token)?;
+    if !matches!(header.alg, Algorithm::RS256 | Algorithm::ES256) {
+        anyhow::bail!("unsupported jwt algorithm {:?}", header.alg)
+    }
+    let Some(kid) = header.kid else {
+        anyhow::bail!("jwt is missing a key id")
+    };
+    let mut keys = jwks(config, false).await?;
+    if keys.find(&kid).is_none() {
+        // keys may have been rotated since they were cached
+        keys = jwks(config, true).await?;
+    }
+    let Some(jwk) = keys.find(&kid) else {
+        anyhow::bail!("no jwk found for key id {kid}")
+    };
+    let mut validation = Validation::new(header.alg);
+    if let Some(issuer) = &config.jwt_issuer {
+        validation.set_issuer(&[issuer]);
+    }
+    match &config.jwt_audience {
+        Some(audience) => validation.set_audience(&[audience]),
+        None => validation.validate_aud = false,
+    }
+    let data = decode::<Value>(token, &DecodingKey::from_jwk(jwk)?, &validation)?;
+    let claims = data.claims;
+    let Some(user_id) = claims.get("sub").and_then(Value::as_str) else {
+        anyhow::bail!("jwt is missing a subject")
+    };
+    let Some(workspace) = claims
+        .get(&config.jwt_workspace_claim)
+        .and_then(Value::as_str)
+    else {
+        anyhow::bail!("jwt is missing the {} claim", config.jwt_workspace_claim)
+    };
+    Ok(Claims {
+        user_id: user_id.to_string(),
+        workspace: workspace.to_string(),
+        scopes: scopes_from_claim(claims.get(&config.jwt_scopes_claim)),
+    })
+}
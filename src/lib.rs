@@ -1,118 +1,310 @@
+pub mod audio;
 pub mod aws;
 pub mod controllers;
 pub mod eleven_labs;
+pub mod env;
 pub mod helpers;
+pub mod jwt;
 pub mod models;
+pub mod quota;
+pub mod retry;
+pub mod secrets;
+pub mod worker;
 
-pub mod env {
-    use std::env::VarError;
+pub mod errors {
+    use std::fmt::Display;
 
-    use serde::{Deserialize, Serialize};
-    use tracing::Level;
+    use lambda_http::http::{HeaderValue, StatusCode};
+    use lambda_web::actix_web::{
+        body::{self, EitherBody},
+        dev::ServiceResponse,
+        error::{self, ResponseError},
+        http, HttpResponse,
+    };
+    use mongoose::types::MongooseError;
+    use serde::Serialize;
+    use serde_json::{json, Value};
+    use thiserror::Error;
 
-    #[derive(Debug, Deserialize, Serialize)]
-    pub enum Stage {
-        Local,
-        Test,
-        Prod,
-        Other(String),
+    use crate::{eleven_labs::ProviderError, models::DuplicateKey};
+
+    pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+    const SERVER_ERROR_DETAIL: &str = "the request could not be completed";
+
+    // rfc 7807 problem details
+    #[derive(Debug, Serialize)]
+    pub struct Problem {
+        #[serde(rename = "type")]
+        pub problem_type: String,
+        pub title: String,
+        pub status: u16,
+        pub detail: String,
+        pub code: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub details: Option<Value>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub request_id: Option<String>,
     }
 
-    #[derive(Debug)]
-    pub struct Config {
-        pub stage: Stage,
-        pub log_level: Level,
-        pub eleven_labs_api_key: String,
-        pub authentication_token: String,
-        pub create_output_queue_url: String,
-        pub train_voice_queue_url: String,
-        pub samples_bucket_name: String,
-        pub outputs_bucket_name: String,
-    }
-
-    impl Config {
-        pub fn new() -> Result<Self, VarError> {
-            if cfg!(debug_assertions) {
-                use dotenv::dotenv;
-                dotenv().ok();
-            }
-            Ok(Self {
-                log_level: match std::env::var("LOG_LEVEL")?.to_uppercase().as_ref() {
-                    "DEBUG" => Level::DEBUG,
-                    "INFO" => Level::INFO,
-                    "ERROR" => Level::ERROR,
-                    _ => Level::ERROR,
+    #[derive(Error, Debug, Clone)]
+    pub enum AppError {
+        #[error("{error}")]
+        Validation {
+            code: &'static str,
+            error: String,
+            details: Option<Value>,
+        },
+        #[error("{error}")]
+        Unauthorized { error: String },
+        #[error("{error}")]
+        Forbidden { error: String },
+        #[error("{error}")]
+        NotFound { code: &'static str, error: String },
+        #[error("{error}")]
+        Conflict {
+            code: &'static str,
+            error: String,
+            details: Option<Value>,
+        },
+        // request rate limits
+        #[error("{error}")]
+        TooManyRequests {
+            error: String,
+            limit: u64,
+            retry_after: u64,
+        },
+        // character quotas
+        #[error("{error}")]
+        QuotaExceeded {
+            error: String,
+            limit: u64,
+            retry_after: u64,
+        },
+        #[error("{error}")]
+        Upstream {
+            code: &'static str,
+            error: String,
+            details: Option<Value>,
+        },
+        #[error("{error}")]
+        InternalServerError { error: String },
+    }
+
+    impl AppError {
+        pub fn validation(code: &'static str, error: impl Into<String>) -> Self {
+            Self::Validation {
+                code,
+                error: error.into(),
+                details: None,
+            }
+        }
+
+        pub fn not_found(code: &'static str, error: impl Into<String>) -> Self {
+            Self::NotFound {
+                code,
+                error: error.into(),
+            }
+        }
+
+        pub fn conflict(code: &'static str, error: impl Into<String>) -> Self {
+            Self::Conflict {
+                code,
+                error: error.into(),
+                details: None,
+            }
+        }
+
+        // stable and machine readable, unlike the message
+        pub const fn code(&self) -> &'static str {
+            match self {
+                Self::Validation { code, .. }
+                | Self::NotFound { code, .. }
+                | Self::Conflict { code, .. }
+                | Self::Upstream { code, .. } => code,
+                Self::Unauthorized { .. } => "unauthorized",
+                Self::Forbidden { .. } => "forbidden",
+                Self::TooManyRequests { .. } => "rate_limited",
+                Self::QuotaExceeded { .. } => "quota_exceeded",
+                Self::InternalServerError { .. } => "internal_server_error",
+            }
+        }
+
+        pub fn details(&self) -> Option<Value> {
+            match self {
+                Self::Validation { details, .. }
+                | Self::Conflict { details, .. }
+                | Self::Upstream { details, .. } => details.clone(),
+                Self::TooManyRequests {
+                    limit, retry_after, ..
+                }
+                | Self::QuotaExceeded {
+                    limit, retry_after, ..
+                } => Some(json!({ "limit": limit, "retry_after": retry_after })),
+                _ => None,
+            }
+        }
+    }
+
+    impl AppError {
+        pub fn problem(&self, request_id: Option<&str>) -> Problem {
+            let status = self.status_code();
+            Problem {
+                problem_type: format!("urn:parrot:problem:{}", self.code()),
+                title: status.canonical_reason().unwrap_or("Error").to_string(),
+                status: status.as_u16(),
+                // the cause is only logged, it can name internals
+                detail: if status.is_server_error() {
+                    SERVER_ERROR_DETAIL.to_string()
+                } else {
+                    self.to_string()
+                },
+                code: self.code().to_string(),
+                details: self.details(),
+                request_id: request_id.map(ToString::to_string),
+            }
+        }
+
+        pub fn response(&self, request_id: Option<&str>) -> HttpResponse<body::BoxBody> {
+            let mut res = HttpResponse::new(self.status_code());
+            res.headers_mut().insert(
+                http::header::CONTENT_TYPE,
+                HeaderValue::from_static(PROBLEM_CONTENT_TYPE),
+            );
+            let limit_headers = match self {
+                Self::TooManyRequests {
+                    limit, retry_after, ..
+                } => Some((
+                    "x-ratelimit-limit",
+                    "x-ratelimit-remaining",
+                    limit,
+                    retry_after,
+                )),
+                Self::QuotaExceeded {
+                    limit, retry_after, ..
+                } => Some((
+                    "x-character-quota-limit",
+                    "x-character-quota-remaining",
+                    limit,
+                    retry_after,
+                )),
+                _ => None,
+            };
+            if let Some((limit_header, remaining_header, limit, retry_after)) = limit_headers {
+                let headers = res.headers_mut();
+                headers.insert(http::header::RETRY_AFTER, HeaderValue::from(*retry_after));
+                headers.insert(
+                    http::header::HeaderName::from_static(limit_header),
+                    HeaderValue::from(*limit),
+                );
+                headers.insert(
+                    http::header::HeaderName::from_static(remaining_header),
+                    HeaderValue::from(0),
+                );
+            }
+            let body = serde_json::to_string(&self.problem(request_id)).unwrap_or_default();
+            res.set_body(body::BoxBody::new(body))
+        }
+    }
+
+    impl From<&MongooseError> for AppError {
+        fn from(err: &MongooseError) -> Self {
+            match err {
+                MongooseError::NotFound(collection) => Self::NotFound {
+                    code: "not_found",
+                    error: format!("no {collection} document found"),
                 },
-                stage: match std::env::var("STAGE")?.to_uppercase().as_str() {
-                    "LOCAL" => Stage::Local,
-                    "PROD" => Stage::Prod,
-                    "TEST" => Stage::Test,
-                    other => Stage::Other(other.to_string()),
+                _ => Self::InternalServerError {
+                    error: err.to_string(),
                 },
-                eleven_labs_api_key: std::env::var("ELEVEN_LABS_API_KEY")?,
-                authentication_token: std::env::var("AUTHENTICATION_TOKEN")?,
-                create_output_queue_url: std::env::var("CREATE_OUTPUT_QUEUE_URL")?,
-                train_voice_queue_url: std::env::var("TRAIN_VOICE_QUEUE_URL")?,
-                samples_bucket_name: std::env::var("SAMPLES_BUCKET_NAME")?,
-                outputs_bucket_name: std::env::var("OUTPUTS_BUCKET_NAME")?,
-            })
+            }
         }
     }
-}
 
-pub mod errors {
-    use std::fmt::Display;
+    impl From<&ProviderError> for AppError {
+        fn from(err: &ProviderError) -> Self {
+            let code = match err {
+                ProviderError::Unauthorized { .. } => "provider_unauthorized",
+                ProviderError::QuotaExceeded { .. } => "provider_quota_exceeded",
+                ProviderError::VoiceNotFound { .. } => "provider_voice_not_found",
+                ProviderError::Validation { .. } => "provider_rejected_request",
+                ProviderError::RateLimited { .. } => "provider_rate_limited",
+                ProviderError::ServerError { .. } => "provider_error",
+                ProviderError::Unavailable { .. } => "provider_unavailable",
+                ProviderError::CircuitOpen { .. } => "provider_circuit_open",
+            };
+            Self::Upstream {
+                code,
+                error: err.to_string(),
+                details: Some(json!({ "provider": "eleven_labs", "status": err.status() })),
+            }
+        }
+    }
 
-    use lambda_http::http::{HeaderValue, StatusCode};
-    use lambda_web::actix_web::{body, error, http, HttpResponse};
-    use thiserror::Error;
+    impl From<&anyhow::Error> for AppError {
+        fn from(err: &anyhow::Error) -> Self {
+            if let Some(err) = err.downcast_ref::<Self>() {
+                return err.clone();
+            }
+            if let Some(err) = err.downcast_ref::<MongooseError>() {
+                return Self::from(err);
+            }
+            if let Some(err) = err.downcast_ref::<DuplicateKey>() {
+                return Self::conflict("conflict", err.to_string());
+            }
+            if let Some(err) = err.downcast_ref::<ProviderError>() {
+                return Self::from(err);
+            }
+            Self::InternalServerError {
+                error: err.to_string(),
+            }
+        }
+    }
 
-    #[derive(Error, Debug)]
-    pub enum AppError {
-        #[error("{error:?}")]
-        Unauthorized { error: Option<String> },
-        #[error("{error:?}")]
-        InternalServerError { error: Option<String> },
-        #[error("{error:?}")]
-        NotFound { error: Option<String> },
+    // errors raised by actix itself, e.g. malformed json bodies, are treated as validation errors
+    impl From<&error::Error> for AppError {
+        fn from(err: &error::Error) -> Self {
+            if let Some(err) = err.as_error::<Self>() {
+                return err.clone();
+            }
+            if let Some(err) = err.as_error::<ApiError>() {
+                return Self::from(&err.0);
+            }
+            if err.as_response_error().status_code().is_client_error() {
+                return Self::validation("invalid_request", err.to_string());
+            }
+            Self::InternalServerError {
+                error: err.to_string(),
+            }
+        }
     }
 
-    impl error::ResponseError for AppError {
+    impl ResponseError for AppError {
         fn status_code(&self) -> StatusCode {
             match self {
-                Self::Unauthorized { error: _ } => StatusCode::UNAUTHORIZED,
-                Self::InternalServerError { error: _ } => StatusCode::INTERNAL_SERVER_ERROR,
-                Self::NotFound { error: _ } => StatusCode::NOT_FOUND,
+                Self::Validation { .. } => StatusCode::BAD_REQUEST,
+                Self::Unauthorized { .. } => StatusCode::UNAUTHORIZED,
+                Self::Forbidden { .. } => StatusCode::FORBIDDEN,
+                Self::NotFound { .. } => StatusCode::NOT_FOUND,
+                Self::Conflict { .. } => StatusCode::CONFLICT,
+                Self::TooManyRequests { .. } | Self::QuotaExceeded { .. } => {
+                    StatusCode::TOO_MANY_REQUESTS
+                }
+                Self::Upstream { .. } => StatusCode::BAD_GATEWAY,
+                Self::InternalServerError { .. } => StatusCode::INTERNAL_SERVER_ERROR,
             }
         }
 
+        // logged by `with_request_id`, which knows the request
         fn error_response(&self) -> HttpResponse<body::BoxBody> {
-            tracing::error!("[ERROR]: {self:?}");
-            let mut res = HttpResponse::new(self.status_code());
-            res.headers_mut().insert(
-                http::header::CONTENT_TYPE,
-                HeaderValue::from_static("application/json"),
-            );
-            let message = match self {
-                Self::Unauthorized { error } => error
-                    .as_ref()
-                    .map_or("unauthorized".to_string(), std::string::ToString::to_string),
-                Self::InternalServerError { error } => error.as_ref().map_or(
-                    "internal server error".to_string(),
-                    std::string::ToString::to_string,
-                ),
-                Self::NotFound { error } => error
-                    .as_ref()
-                    .map_or("not found".to_string(), std::string::ToString::to_string),
-            };
-            let error_raw = format!(r#"{{"error":"{message}"}}"#);
-            res.set_body(body::BoxBody::new(error_raw))
+            self.response(None)
         }
     }
 
     pub type AppResponse = Result<HttpResponse, AppError>;
 
     // THIS IS FOR MORE GENERIC ERRORS WITH ANYHOW
+    // responses are built from the AppError the underlying error maps to
     #[derive(Debug)]
     pub struct ApiError(anyhow::Error);
     pub type ApiResponse = Result<HttpResponse, ApiError>;
@@ -132,21 +324,39 @@ pub mod errors {
         }
     }
 
-    impl error::ResponseError for ApiError {
+    impl ResponseError for ApiError {
         fn status_code(&self) -> StatusCode {
-            StatusCode::INTERNAL_SERVER_ERROR
+            AppError::from(&self.0).status_code()
         }
 
         fn error_response(&self) -> HttpResponse<body::BoxBody> {
-            tracing::error!("[ERROR]: {self:?}");
-            let mut res = HttpResponse::new(self.status_code());
-            res.headers_mut().insert(
-                http::header::CONTENT_TYPE,
-                HeaderValue::from_static("application/json"),
-            );
-            let error_raw = format!(r#"{{"error":"{self}"}}"#);
-            res.set_body(body::BoxBody::new(error_raw))
+            AppError::from(&self.0).error_response()
+        }
+    }
+
+    // problem responses only know the request id once the request has been handled
+    pub fn with_request_id<B>(
+        res: ServiceResponse<B>,
+        request_id: &str,
+    ) -> ServiceResponse<EitherBody<B>> {
+        let mut res = match res.response().error() {
+            Some(cause) => {
+                let err = AppError::from(cause);
+                if err.status_code().is_server_error() {
+                    tracing::error!("[ERROR] {request_id}: {cause:?}");
+                } else {
+                    tracing::warn!("[ERROR] {request_id}: {cause:?}");
+                }
+                let response = err.response(Some(request_id));
+                res.into_response(response).map_into_right_body()
+            }
+            None => res.map_into_left_body(),
+        };
+        if let Ok(value) = HeaderValue::from_str(request_id) {
+            res.headers_mut()
+                .insert(http::header::HeaderName::from_static("x-request-id"), value);
         }
+        res
     }
 }
 
@@ -155,24 +365,239 @@ pub mod logger {
 
     use crate::env::Config;
 
-    pub fn init() -> anyhow::Result<()> {
-        let log_level = Config::new()?.log_level;
-        let subscriber = FmtSubscriber::builder().with_max_level(log_level).finish();
+    pub fn init(config: &Config) -> anyhow::Result<()> {
+        let subscriber = FmtSubscriber::builder()
+            .with_max_level(config.log_level)
+            .finish();
         tracing::subscriber::set_global_default(subscriber)?;
         Ok(())
     }
 }
 
 pub mod types {
-    use serde::{Deserialize, Serialize};
+    use chrono::{DateTime, Utc};
+    use serde::{de::DeserializeOwned, Deserialize, Serialize};
+    use serde_json::Value;
+    use thiserror::Error;
+
+    use crate::helpers::generate_request_id;
 
-    #[derive(Deserialize, Serialize)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum MessageType {
+        CreateOutput,
+        TrainSample,
+    }
+
+    pub const LEGACY_VERSION: u32 = 0;
+
+    // bumping the version is how a payload changes shape, workers only accept the one they know
+    pub trait MessagePayload: Serialize + DeserializeOwned {
+        const TYPE: MessageType;
+        const VERSION: u32;
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
     pub struct CreateOutputFifoMessage {
         pub output_id: String,
     }
 
-    #[derive(Deserialize, Serialize)]
+    impl MessagePayload for CreateOutputFifoMessage {
+        const TYPE: MessageType = MessageType::CreateOutput;
+        const VERSION: u32 = 1;
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
     pub struct TrainSampleFifoMessage {
         pub voice_id: String,
     }
+
+    impl MessagePayload for TrainSampleFifoMessage {
+        const TYPE: MessageType = MessageType::TrainSample;
+        const VERSION: u32 = 1;
+    }
+
+    #[derive(Error, Debug)]
+    pub enum EnvelopeError {
+        #[error("expected a {expected:?} message, got {found:?}")]
+        WrongType {
+            expected: MessageType,
+            found: MessageType,
+        },
+        #[error("unsupported {message_type:?} message version {version}, expected {expected}")]
+        UnsupportedVersion {
+            message_type: MessageType,
+            version: u32,
+            expected: u32,
+        },
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Envelope<T> {
+        #[serde(rename = "type")]
+        pub message_type: MessageType,
+        pub version: u32,
+        pub enqueued_at: DateTime<Utc>,
+        pub request_id: Option<String>,
+        // follows the work from the api through the queue to the worker logs
+        pub correlation_id: String,
+        pub attempt: u32,
+        pub actor: String,
+        pub payload: T,
+    }
+
+    impl<T: MessagePayload> Envelope<T> {
+        pub fn new(payload: T, actor: &str, request_id: Option<&str>) -> Self {
+            Self {
+                message_type: T::TYPE,
+                version: T::VERSION,
+                enqueued_at: Utc::now(),
+                request_id: request_id.map(ToString::to_string),
+                correlation_id: request_id.map_or_else(generate_request_id, ToString::to_string),
+                attempt: 1,
+                actor: actor.to_string(),
+                payload,
+            }
+        }
+
+        // bodies queued before envelopes are the bare payload, read as version 0
+        fn legacy(payload: T) -> Self {
+            Self {
+                version: LEGACY_VERSION,
+                ..Self::new(payload, "unknown", None)
+            }
+        }
+
+        // the header is checked before the payload, which may not parse under another version
+        pub fn open(body: &str) -> anyhow::Result<Self> {
+            let value = serde_json::from_str::<Value>(body)?;
+            if value.get("type").is_none() && value.get("version").is_none() {
+                return Ok(Self::legacy(serde_json::from_value(value)?));
+            }
+            let envelope = serde_json::from_value::<Envelope<Value>>(value)?;
+            if envelope.message_type != T::TYPE {
+                return Err(EnvelopeError::WrongType {
+                    expected: T::TYPE,
+                    found: envelope.message_type,
+                }
+                .into());
+            }
+            if envelope.version != T::VERSION {
+                return Err(EnvelopeError::UnsupportedVersion {
+                    message_type: envelope.message_type,
+                    version: envelope.version,
+                    expected: T::VERSION,
+                }
+                .into());
+            }
+            Ok(Envelope {
+                message_type: envelope.message_type,
+                version: envelope.version,
+                enqueued_at: envelope.enqueued_at,
+                request_id: envelope.request_id,
+                correlation_id: envelope.correlation_id,
+                attempt: envelope.attempt,
+                actor: envelope.actor,
+                payload: serde_json::from_value(envelope.payload)?,
+            })
+        }
+    }
+
+    // a redriven message counts as another attempt, bodies that are not envelopes pass through
+    pub fn next_attempt(body: &str) -> String {
+        let Ok(mut value) = serde_json::from_str::<Value>(body) else {
+            return body.to_string();
+        };
+        let Some(attempt) = value.get("attempt").and_then(Value::as_u64) else {
+            return body.to_string();
+        };
+        value["attempt"] = Value::from(attempt + 1);
+        value.to_string()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use serde_json::json;
+
+        use super::*;
+
+        #[test]
+        fn open_reads_back_a_new_envelope() {
+            let payload = CreateOutputFifoMessage {
+                output_id: "output".to_string(),
+            };
+            let body =
+                serde_json::to_string(&Envelope::new(payload, "key:a", Some("req"))).unwrap();
+            let envelope = Envelope::<CreateOutputFifoMessage>::open(&body).unwrap();
+            assert_eq!(envelope.payload.output_id, "output");
+            assert_eq!(envelope.version, CreateOutputFifoMessage::VERSION);
+            assert_eq!(envelope.correlation_id, "req");
+            assert_eq!((envelope.attempt, envelope.actor.as_str()), (1, "key:a"));
+        }
+
+        #[test]
+        fn open_rejects_a_message_for_another_worker() {
+            let body = json!({
+                "type": "train_sample",
+                "version": 1,
+                "enqueued_at": "2024-01-01T00:00:00Z",
+                "correlation_id": "req",
+                "attempt": 1,
+                "actor": "key:a",
+                "payload": { "voice_id": "voice" },
+            });
+            let err = Envelope::<CreateOutputFifoMessage>::open(&body.to_string()).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref(),
+                Some(EnvelopeError::WrongType {
+                    found: MessageType::TrainSample,
+                    ..
+                })
+            ));
+        }
+
+        #[test]
+        fn open_rejects_another_version_without_reading_its_payload() {
+            let body = json!({
+                "type": "create_output",
+                "version": 2,
+                "enqueued_at": "2024-01-01T00:00:00Z",
+                "correlation_id": "req",
+                "attempt": 1,
+                "actor": "key:a",
+                "payload": { "output_ids": ["output"] },
+            });
+            let err = Envelope::<CreateOutputFifoMessage>::open(&body.to_string()).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref(),
+                Some(EnvelopeError::UnsupportedVersion { version: 2, .. })
+            ));
+        }
+
+        #[test]
+        fn open_reads_a_bare_legacy_payload_as_version_zero() {
+            let body = json!({ "output_id": "output" }).to_string();
+            let envelope = Envelope::<CreateOutputFifoMessage>::open(&body).unwrap();
+            assert_eq!(envelope.payload.output_id, "output");
+            assert_eq!(envelope.message_type, MessageType::CreateOutput);
+            assert_eq!((envelope.version, envelope.attempt), (LEGACY_VERSION, 1));
+        }
+
+        #[test]
+        fn open_rejects_bodies_that_do_not_parse() {
+            let body = json!({ "voice_id": "voice" }).to_string();
+            for body in ["not json", &body] {
+                let err = Envelope::<CreateOutputFifoMessage>::open(body).unwrap_err();
+                assert!(err.downcast_ref::<serde_json::Error>().is_some(), "{body}");
+            }
+        }
+
+        #[test]
+        fn next_attempt_counts_up_envelopes_and_passes_anything_else_through() {
+            let body = json!({ "type": "create_output", "attempt": 2 }).to_string();
+            let next = serde_json::from_str::<Value>(&next_attempt(&body)).unwrap();
+            assert_eq!(next["attempt"], 3);
+            assert_eq!(next_attempt("not json"), "not json");
+        }
+    }
 }
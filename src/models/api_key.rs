@@ -0,0 +1,135 @@
+use mongoose::{
+    bson::{doc, DateTime},
+    mongodb::{options::IndexOptions, results::CreateIndexesResult, IndexModel},
+    types::MongooseError,
+    Model,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::quota::Limits;
+
+const KEY_PREFIX: &str = "prt_";
+const KEY_LENGTH: usize = 40;
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    #[serde(rename = "voices:read")]
+    VoicesRead,
+    #[serde(rename = "voices:write")]
+    VoicesWrite,
+    #[serde(rename = "outputs:create")]
+    OutputsCreate,
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scope::VoicesRead => write!(f, "voices:read"),
+            Scope::VoicesWrite => write!(f, "voices:write"),
+            Scope::OutputsCreate => write!(f, "outputs:create"),
+            Scope::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+impl std::str::FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(scope: &str) -> Result<Self, Self::Err> {
+        match scope {
+            "voices:read" => Ok(Scope::VoicesRead),
+            "voices:write" => Ok(Scope::VoicesWrite),
+            "outputs:create" => Ok(Scope::OutputsCreate),
+            "admin" => Ok(Scope::Admin),
+            other => anyhow::bail!("unknown scope {other}"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ApiKey {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub workspace: String,
+    pub name: String,
+    // first characters of the key, so it can be recognized without storing it
+    pub prefix: String,
+    // emptied by `redacted` so it never leaves the api
+    #[serde(skip_serializing_if = "String::is_empty", default)]
+    pub hash: String,
+    pub scopes: Vec<Scope>,
+    #[serde(default)]
+    pub limits: Limits,
+    pub expires_at: Option<DateTime>,
+    pub last_used_at: Option<DateTime>,
+    pub revoked_at: Option<DateTime>,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+impl Default for ApiKey {
+    fn default() -> Self {
+        Self {
+            id: Self::generate_nanoid(),
+            workspace: std::string::String::default(),
+            name: std::string::String::default(),
+            prefix: std::string::String::default(),
+            hash: std::string::String::default(),
+            scopes: vec![],
+            expires_at: None,
+            last_used_at: None,
+            revoked_at: None,
+            limits: Limits::default(),
+            created_at: DateTime::now(),
+            updated_at: DateTime::now(),
+        }
+    }
+}
+
+impl Model for ApiKey {}
+
+impl ApiKey {
+    pub async fn migrate() -> Result<CreateIndexesResult, MongooseError> {
+        Self::create_indexes(&[
+            IndexModel::builder()
+                .keys(doc! { "hash": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+            IndexModel::builder().keys(doc! { "workspace": 1 }).build(),
+        ])
+        .await
+    }
+
+    pub fn generate_secret() -> String {
+        let secret = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(KEY_LENGTH)
+            .map(char::from)
+            .collect::<String>();
+        format!("{KEY_PREFIX}{secret}")
+    }
+
+    pub fn hash_secret(secret: &str) -> String {
+        hex::encode(Sha256::digest(secret.as_bytes()))
+    }
+
+    pub async fn find_by_secret(secret: &str) -> Result<Self, MongooseError> {
+        Self::read(doc! { "hash": Self::hash_secret(secret), "revoked_at": null }).await
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= DateTime::now())
+    }
+
+    pub fn redacted(self) -> Self {
+        Self {
+            hash: String::new(),
+            ..self
+        }
+    }
+}
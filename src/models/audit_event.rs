@@ -0,0 +1,125 @@
+use mongoose::{
+    bson::{doc, DateTime},
+    mongodb::{results::CreateIndexesResult, IndexModel},
+    types::MongooseError,
+    Model,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::models::insert_once;
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum AuditAction {
+    SampleUploadRequested,
+    ConsentRecorded,
+    ConsentVerified,
+    VoiceTrainingStarted,
+    VoiceTrained,
+    VoiceTrainingFailed,
+    // training found no verified consent
+    VoiceReturnedToDraft,
+    // the eleven labs voice it pointed at no longer exists
+    VoiceMarkedFailed,
+    VoiceUpdated,
+    VoiceDeleted,
+    VoiceRestored,
+    VoiceImported,
+    VoicePurged,
+    ProviderVoiceDeleted,
+    OutputRequested,
+    OutputCreated,
+    ApiKeyCreated,
+    ApiKeyRevoked,
+    WorkspaceCreated,
+    WorkspaceUpdated,
+}
+
+// audit events are append only, nothing updates or deletes them
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AuditEvent {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub workspace: String,
+    // key:{id}, user:{id}, root or a worker name
+    pub actor: String,
+    pub action: AuditAction,
+    // {kind}:{id} of the document acted on
+    pub target: String,
+    pub request_id: Option<String>,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+impl Default for AuditEvent {
+    fn default() -> Self {
+        Self {
+            id: Self::generate_nanoid(),
+            workspace: std::string::String::default(),
+            actor: std::string::String::default(),
+            action: AuditAction::OutputRequested,
+            target: std::string::String::default(),
+            request_id: None,
+            created_at: DateTime::now(),
+            updated_at: DateTime::now(),
+        }
+    }
+}
+
+impl Model for AuditEvent {}
+
+impl AuditEvent {
+    pub async fn record(
+        workspace: &str,
+        actor: &str,
+        action: AuditAction,
+        target: String,
+        request_id: Option<&str>,
+    ) -> Result<Self, MongooseError> {
+        Self {
+            workspace: workspace.to_string(),
+            actor: actor.to_string(),
+            action,
+            target,
+            request_id: request_id.map(ToString::to_string),
+            ..Default::default()
+        }
+        .save()
+        .await
+    }
+
+    // for workers, which can be retried after recording, `id` names the unit of work
+    pub async fn record_once(
+        id: String,
+        workspace: &str,
+        actor: &str,
+        action: AuditAction,
+        target: String,
+        request_id: Option<&str>,
+    ) -> Result<(), MongooseError> {
+        insert_once(&Self {
+            id,
+            workspace: workspace.to_string(),
+            actor: actor.to_string(),
+            action,
+            target,
+            request_id: request_id.map(ToString::to_string),
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub async fn migrate() -> Result<CreateIndexesResult, MongooseError> {
+        Self::create_indexes(&[
+            IndexModel::builder()
+                .keys(doc! { "workspace": 1, "created_at": -1 })
+                .build(),
+            IndexModel::builder()
+                .keys(doc! { "workspace": 1, "target": 1, "created_at": -1 })
+                .build(),
+            IndexModel::builder()
+                .keys(doc! { "workspace": 1, "actor": 1, "created_at": -1 })
+                .build(),
+        ])
+        .await
+    }
+}
@@ -0,0 +1,84 @@
+use mongoose::{
+    bson::{doc, DateTime},
+    mongodb::{options::IndexOptions, results::CreateIndexesResult, IndexModel},
+    types::MongooseError,
+    Model,
+};
+use serde::{Deserialize, Serialize};
+
+// kept apart from the voice so the record outlives a purged voice
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Consent {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub workspace: String,
+    pub voice: String,
+    // whose voice is being cloned
+    pub speaker_name: String,
+    pub statement: String,
+    // who signed on the speaker's behalf, usually the speaker
+    pub signer: String,
+    pub signed_at: DateTime,
+    // set when a consent recording was requested alongside the sample
+    pub recording_key: Option<String>,
+    pub actor: String,
+    pub verified_at: Option<DateTime>,
+    pub verified_by: Option<String>,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+impl Default for Consent {
+    fn default() -> Self {
+        Self {
+            id: Self::generate_nanoid(),
+            workspace: std::string::String::default(),
+            voice: std::string::String::default(),
+            speaker_name: std::string::String::default(),
+            statement: std::string::String::default(),
+            signer: std::string::String::default(),
+            signed_at: DateTime::now(),
+            recording_key: None,
+            actor: std::string::String::default(),
+            verified_at: None,
+            verified_by: None,
+            created_at: DateTime::now(),
+            updated_at: DateTime::now(),
+        }
+    }
+}
+
+impl Model for Consent {}
+
+impl Consent {
+    pub async fn migrate() -> Result<CreateIndexesResult, MongooseError> {
+        Self::create_indexes(&[IndexModel::builder()
+            .keys(doc! { "voice": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build()])
+        .await
+    }
+
+    // consent recordings share the samples bucket, under their own prefix
+    pub fn recording_key(workspace: &str, voice: &str) -> String {
+        format!("{workspace}/consent/{voice}.mp3")
+    }
+
+    pub fn is_recording_key(key: &str) -> bool {
+        key.contains("/consent/")
+    }
+
+    pub async fn read_for_voice(voice: &str) -> Result<Self, MongooseError> {
+        Self::read(doc! { "voice": voice }).await
+    }
+
+    pub const fn is_verified(&self) -> bool {
+        self.verified_at.is_some()
+    }
+
+    pub async fn is_verified_for(voice: &str) -> bool {
+        Self::read_for_voice(voice)
+            .await
+            .is_ok_and(|consent| consent.is_verified())
+    }
+}
@@ -1,2 +1,114 @@
+use mongoose::{
+    bson::{doc, to_document, Document},
+    mongodb::{
+        error::{CommandError, Error, ErrorKind, WriteError, WriteFailure},
+        options::{FindOneAndUpdateOptions, ReturnDocument, UpdateOptions},
+    },
+    types::MongooseError,
+    Model,
+};
+
+pub mod api_key;
+pub mod audit_event;
+pub mod consent;
 pub mod output;
+pub mod provider_voice;
+pub mod usage_counter;
+pub mod usage_event;
 pub mod voice;
+pub mod workspace;
+
+const NAMESPACE_NOT_FOUND: i32 = 26;
+const INDEX_NOT_FOUND: i32 = 27;
+const DUPLICATE_KEY: i32 = 11000;
+
+#[derive(Debug, thiserror::Error)]
+#[error("a {0} document with the same unique fields already exists")]
+pub struct DuplicateKey(pub String);
+
+// inserts report a write error, updates through find and modify a command error
+fn is_duplicate_key(err: &Error) -> bool {
+    match *err.kind {
+        ErrorKind::Write(WriteFailure::WriteError(WriteError { code, .. }))
+        | ErrorKind::Command(CommandError { code, .. }) => code == DUPLICATE_KEY,
+        _ => false,
+    }
+}
+
+// mongo will not redefine an index in place, so ones whose keys or options changed are dropped first
+pub async fn drop_indexes<M: Model>(names: &[&str]) -> Result<(), MongooseError> {
+    let collection = M::collection().await;
+    for name in names {
+        match collection.drop_index(*name, None).await {
+            Ok(()) => tracing::info!("dropped {:?} index {name}", M::name()),
+            Err(err) => match *err.kind {
+                ErrorKind::Command(CommandError { code, .. })
+                    if code == NAMESPACE_NOT_FOUND || code == INDEX_NOT_FOUND => {}
+                _ => {
+                    tracing::error!("error dropping {:?} index {name}: {:?}", M::name(), err);
+                    return Err(MongooseError::CreateIndex(M::name()));
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+// inserts unless a document with the same id already exists, so work that is retried part way
+// through only records once
+pub async fn insert_once<M: Model>(model: &M) -> Result<(), MongooseError> {
+    let insert_error = |err: &dyn std::fmt::Debug| {
+        tracing::error!("error inserting {:?} document: {:?}", M::name(), err);
+        MongooseError::Insert(M::name())
+    };
+    let mut document = to_document(model).map_err(|err| insert_error(&err))?;
+    let Some(id) = document.remove("_id") else {
+        return Err(insert_error(&"document has no id"));
+    };
+    M::collection()
+        .await
+        .update_one(
+            doc! { "_id": id },
+            doc! { "$setOnInsert": document },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await
+        .map_err(|err| insert_error(&err))?;
+    Ok(())
+}
+
+// `Model::save` drops the mongo error, so inserts that can collide on a unique index go through
+// here to tell a duplicate apart from any other failure
+pub async fn insert<M: Model>(model: &M) -> anyhow::Result<M> {
+    match M::collection().await.insert_one(model, None).await {
+        Ok(_) => Ok(model.clone()),
+        Err(err) if is_duplicate_key(&err) => Err(DuplicateKey(M::name()).into()),
+        Err(err) => {
+            tracing::error!("error inserting {:?} document: {:?}", M::name(), err);
+            Err(MongooseError::Insert(M::name()).into())
+        }
+    }
+}
+
+// the same for `Model::update`, e.g. renaming or restoring a voice can collide with a live one
+pub async fn update<M: Model>(filter: Document, updates: Document) -> anyhow::Result<M> {
+    match M::collection()
+        .await
+        .find_one_and_update(
+            filter,
+            M::normalize_updates(&updates),
+            FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await
+    {
+        Ok(Some(updated)) => Ok(updated),
+        Ok(None) => Err(MongooseError::NotFound(M::name()).into()),
+        Err(err) if is_duplicate_key(&err) => Err(DuplicateKey(M::name()).into()),
+        Err(err) => {
+            tracing::error!("error updating {:?} document: {:?}", M::name(), err);
+            Err(MongooseError::Update(M::name()).into())
+        }
+    }
+}
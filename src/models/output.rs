@@ -6,7 +6,11 @@ use mongoose::{
 };
 use serde::{Deserialize, Serialize};
 
-use crate::models::voice::Voice;
+use crate::{
+    audio::AudioMetadata,
+    models::{drop_indexes, voice::Voice},
+    quota,
+};
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub enum OutputStatus {
@@ -14,11 +18,11 @@ pub enum OutputStatus {
     Done,
 }
 
-impl OutputStatus {
-    pub fn to_string(&self) -> String {
+impl std::fmt::Display for OutputStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            OutputStatus::Pending => "Pending".to_string(),
-            OutputStatus::Done => "Done".to_string(),
+            OutputStatus::Pending => write!(f, "Pending"),
+            OutputStatus::Done => write!(f, "Done"),
         }
     }
 }
@@ -27,9 +31,17 @@ impl OutputStatus {
 pub struct Output {
     #[serde(rename = "_id")]
     pub id: String,
+    pub workspace: String,
     pub voice: String,
     pub text: String,
     pub status: OutputStatus,
+    pub metadata: Option<AudioMetadata>,
+    #[serde(default)]
+    pub actor: String,
+    // the usage counters the text was charged to, handed back if the output is never made
+    #[serde(default)]
+    pub charged_counters: Vec<String>,
+    pub refunded_at: Option<DateTime>,
     pub created_at: DateTime,
     pub updated_at: DateTime,
 }
@@ -38,9 +50,14 @@ impl Default for Output {
     fn default() -> Self {
         Self {
             id: Self::generate_nanoid(),
+            workspace: std::string::String::default(),
             voice: std::string::String::default(),
             text: std::string::String::default(),
             status: OutputStatus::Pending,
+            metadata: None,
+            actor: std::string::String::default(),
+            charged_counters: vec![],
+            refunded_at: None,
             created_at: DateTime::now(),
             updated_at: DateTime::now(),
         }
@@ -53,19 +70,25 @@ impl Model for Output {}
 pub struct PopulatedOutput {
     #[serde(rename = "_id")]
     pub id: String,
+    pub workspace: String,
     pub voice: Voice,
     pub text: String,
     pub status: OutputStatus,
+    pub metadata: Option<AudioMetadata>,
     pub created_at: DateTime,
     pub updated_at: DateTime,
 }
 
 impl Output {
     pub async fn migrate() -> Result<CreateIndexesResult, MongooseError> {
-        Self::create_indexes(&vec![
-            IndexModel::builder().keys(doc! { "voice": 1 }).build(),
+        // replaced by the workspace scoped indexes, a collection only allows one text index
+        drop_indexes::<Self>(&["voice_1", "text_text"]).await?;
+        Self::create_indexes(&[
+            IndexModel::builder()
+                .keys(doc! { "workspace": 1, "voice": 1 })
+                .build(),
             IndexModel::builder()
-                .keys(doc! { "text": "text" })
+                .keys(doc! { "workspace": 1, "text": "text" })
                 .options(
                     IndexOptions::builder()
                         .default_language("english".to_string())
@@ -76,9 +99,62 @@ impl Output {
         .await
     }
 
-    pub async fn search_text(term: &str) -> Result<Vec<PopulatedOutput>, MongooseError> {
+    pub async fn read_in_workspace(workspace: &str, id: &str) -> Result<Self, MongooseError> {
+        Self::read(doc! { "_id": id, "workspace": workspace }).await
+    }
+
+    pub fn object_key(&self) -> String {
+        format!("{}/{}.mp3", self.workspace, self.id)
+    }
+
+    pub fn characters(&self) -> anyhow::Result<u64> {
+        Ok(u64::try_from(self.text.chars().count())?)
+    }
+
+    // at most once, and only while the output has not been made
+    pub async fn refund_characters(&self) -> anyhow::Result<()> {
+        let empty_date: Option<DateTime> = None;
+        match Self::update(
+            doc! {
+                "_id": &self.id,
+                "status": OutputStatus::Pending.to_string(),
+                "refunded_at": empty_date,
+            },
+            doc! { "refunded_at": DateTime::now() },
+        )
+        .await
+        {
+            Ok(output) => {
+                quota::refund_characters(&output.charged_counters, output.characters()?).await
+            }
+            Err(MongooseError::NotFound(_)) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // a refunded output that is queued again is charged again
+    pub async fn recharge_characters(&self) -> anyhow::Result<()> {
+        let empty_date: Option<DateTime> = None;
+        match Self::update(
+            doc! { "_id": &self.id, "refunded_at": { "$ne": empty_date } },
+            doc! { "refunded_at": empty_date },
+        )
+        .await
+        {
+            Ok(output) => {
+                quota::recharge_characters(&output.charged_counters, output.characters()?).await
+            }
+            Err(MongooseError::NotFound(_)) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub async fn search_text(
+        workspace: &str,
+        term: &str,
+    ) -> Result<Vec<PopulatedOutput>, MongooseError> {
         let pipeline = vec![
-            doc! { "$match": { "$text": { "$search": term } }},
+            doc! { "$match": { "workspace": workspace, "$text": { "$search": term } }},
             doc! { "$lookup": {
                 "from": Voice::name(),
                 "localField": "voice",
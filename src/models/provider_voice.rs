@@ -0,0 +1,94 @@
+use mongoose::{
+    bson::{doc, DateTime},
+    types::MongooseError,
+    Model,
+};
+use serde::{Deserialize, Serialize};
+
+// every eleven labs voice parrot has cloned or imported, keyed by the eleven labs id and kept
+// after the voice is purged, so reconciliation only ever deletes voices parrot created itself
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ProviderVoice {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub workspace: String,
+    pub voice: String,
+    // never unset, a provider voice that was ever imported is never deleted by parrot
+    pub externally_managed: bool,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+impl Default for ProviderVoice {
+    fn default() -> Self {
+        Self {
+            id: std::string::String::default(),
+            workspace: std::string::String::default(),
+            voice: std::string::String::default(),
+            externally_managed: false,
+            created_at: DateTime::now(),
+            updated_at: DateTime::now(),
+        }
+    }
+}
+
+impl Model for ProviderVoice {}
+
+impl ProviderVoice {
+    pub async fn record_clone(
+        eleven_labs_id: &str,
+        workspace: &str,
+        voice: &str,
+    ) -> Result<Self, MongooseError> {
+        Self {
+            id: eleven_labs_id.to_string(),
+            workspace: workspace.to_string(),
+            voice: voice.to_string(),
+            ..Default::default()
+        }
+        .save()
+        .await
+    }
+
+    pub async fn record_import(
+        eleven_labs_id: &str,
+        workspace: &str,
+        voice: &str,
+    ) -> Result<Self, MongooseError> {
+        match Self::update(
+            doc! { "_id": eleven_labs_id },
+            doc! { "externally_managed": true },
+        )
+        .await
+        {
+            Err(MongooseError::NotFound(_)) => {
+                Self {
+                    id: eleven_labs_id.to_string(),
+                    workspace: workspace.to_string(),
+                    voice: voice.to_string(),
+                    externally_managed: true,
+                    ..Default::default()
+                }
+                .save()
+                .await
+            }
+            result => result,
+        }
+    }
+
+    // cloned by parrot, never imported, and old enough that the clone is not still being linked
+    pub async fn deletable(
+        eleven_labs_ids: &[&str],
+        cloned_before: DateTime,
+    ) -> Result<Vec<Self>, MongooseError> {
+        Self::list(
+            Some(doc! {
+                "_id": { "$in": eleven_labs_ids },
+                "externally_managed": false,
+                "created_at": { "$lte": cloned_before },
+            }),
+            None,
+        )
+        .await
+    }
+}
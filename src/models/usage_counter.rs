@@ -0,0 +1,213 @@
+use chrono::{Datelike, TimeZone, Utc};
+use mongoose::{
+    bson::{doc, DateTime},
+    mongodb::{
+        options::{FindOneAndUpdateOptions, IndexOptions, ReturnDocument},
+        results::CreateIndexesResult,
+        IndexModel,
+    },
+    types::MongooseError,
+    Model,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum Window {
+    Minute,
+    Day,
+    Month,
+}
+
+impl std::fmt::Display for Window {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Window::Minute => write!(f, "Minute"),
+            Window::Day => write!(f, "Day"),
+            Window::Month => write!(f, "Month"),
+        }
+    }
+}
+
+impl Window {
+    // start and end of the window containing `now`, in milliseconds
+    pub fn bounds(self, now: chrono::DateTime<Utc>) -> (i64, i64) {
+        const MINUTE_MS: i64 = 60 * 1000;
+        const DAY_MS: i64 = 24 * 60 * MINUTE_MS;
+        let now_ms = now.timestamp_millis();
+        match self {
+            Window::Minute => {
+                let start = now_ms - now_ms.rem_euclid(MINUTE_MS);
+                (start, start + MINUTE_MS)
+            }
+            Window::Day => {
+                let start = now_ms - now_ms.rem_euclid(DAY_MS);
+                (start, start + DAY_MS)
+            }
+            Window::Month => {
+                let (year, month) = (now.year(), now.month());
+                let (next_year, next_month) = if month == 12 {
+                    (year + 1, 1)
+                } else {
+                    (year, month + 1)
+                };
+                let start = Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0).single();
+                let end = Utc
+                    .with_ymd_and_hms(next_year, next_month, 1, 0, 0, 0)
+                    .single();
+                match (start, end) {
+                    (Some(start), Some(end)) => (start.timestamp_millis(), end.timestamp_millis()),
+                    _ => (now_ms, now_ms),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct UsageCounter {
+    // {subject}:{window}:{window start}
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub subject: String,
+    pub window: Window,
+    pub count: i64,
+    pub expires_at: DateTime,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+impl Default for UsageCounter {
+    fn default() -> Self {
+        Self {
+            id: Self::generate_nanoid(),
+            subject: std::string::String::default(),
+            window: Window::Minute,
+            count: 0,
+            expires_at: DateTime::now(),
+            created_at: DateTime::now(),
+            updated_at: DateTime::now(),
+        }
+    }
+}
+
+impl Model for UsageCounter {}
+
+impl UsageCounter {
+    pub async fn migrate() -> Result<CreateIndexesResult, MongooseError> {
+        Self::create_indexes(&[IndexModel::builder()
+            .keys(doc! { "expires_at": 1 })
+            .options(
+                IndexOptions::builder()
+                    .expire_after(std::time::Duration::from_secs(0))
+                    .build(),
+            )
+            .build()])
+        .await
+    }
+
+    // atomically adds `amount` to the subject's counter for the current window
+    pub async fn increment(subject: &str, window: Window, amount: i64) -> anyhow::Result<Self> {
+        let (start, end) = window.bounds(Utc::now());
+        let id = format!("{subject}:{window}:{start}");
+        let now = DateTime::now();
+        let counter = Self::collection()
+            .await
+            .find_one_and_update(
+                doc! { "_id": &id },
+                doc! {
+                    "$inc": { "count": amount },
+                    "$set": { "updated_at": now },
+                    "$setOnInsert": {
+                        "subject": subject,
+                        "window": window.to_string(),
+                        "expires_at": DateTime::from_millis(end),
+                        "created_at": now,
+                    },
+                },
+                FindOneAndUpdateOptions::builder()
+                    .upsert(true)
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await?;
+        match counter {
+            Some(counter) => Ok(counter),
+            None => anyhow::bail!("error incrementing usage counter {id}"),
+        }
+    }
+
+    // changes an existing counter, one that has expired is left alone
+    pub async fn adjust(id: &str, amount: i64) -> anyhow::Result<()> {
+        Self::collection()
+            .await
+            .update_one(
+                doc! { "_id": id },
+                doc! {
+                    "$inc": { "count": amount },
+                    "$set": { "updated_at": DateTime::now() },
+                },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(time: &str) -> i64 {
+        time.parse::<chrono::DateTime<Utc>>()
+            .unwrap()
+            .timestamp_millis()
+    }
+
+    #[test]
+    fn bounds_contain_the_time_they_are_taken_at() {
+        let now = "2024-03-10T12:34:56Z".parse().unwrap();
+        assert_eq!(
+            Window::Minute.bounds(now),
+            (
+                millis("2024-03-10T12:34:00Z"),
+                millis("2024-03-10T12:35:00Z")
+            )
+        );
+        assert_eq!(
+            Window::Day.bounds(now),
+            (
+                millis("2024-03-10T00:00:00Z"),
+                millis("2024-03-11T00:00:00Z")
+            )
+        );
+        assert_eq!(
+            Window::Month.bounds(now),
+            (
+                millis("2024-03-01T00:00:00Z"),
+                millis("2024-04-01T00:00:00Z")
+            )
+        );
+    }
+
+    #[test]
+    fn a_window_starts_at_its_own_boundary() {
+        let now = "2024-02-01T00:00:00Z".parse().unwrap();
+        for window in [Window::Minute, Window::Day, Window::Month] {
+            assert_eq!(window.bounds(now).0, millis("2024-02-01T00:00:00Z"));
+        }
+        // a leap year february
+        assert_eq!(Window::Month.bounds(now).1, millis("2024-03-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn december_ends_in_the_next_year() {
+        let now = "2023-12-31T23:59:59Z".parse().unwrap();
+        assert_eq!(
+            Window::Month.bounds(now),
+            (
+                millis("2023-12-01T00:00:00Z"),
+                millis("2024-01-01T00:00:00Z")
+            )
+        );
+    }
+}
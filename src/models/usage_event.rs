@@ -0,0 +1,117 @@
+use mongoose::{
+    bson::{doc, DateTime, Document},
+    mongodb::{results::CreateIndexesResult, IndexModel},
+    types::MongooseError,
+    Model,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum UsageKind {
+    Synthesis,
+    Clone,
+}
+
+impl std::fmt::Display for UsageKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UsageKind::Synthesis => write!(f, "Synthesis"),
+            UsageKind::Clone => write!(f, "Clone"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum UsageGroup {
+    #[serde(rename = "day")]
+    Day,
+    #[serde(rename = "voice")]
+    Voice,
+    #[serde(rename = "key")]
+    Key,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct UsageEvent {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub workspace: String,
+    pub kind: UsageKind,
+    pub characters: u64,
+    pub model: Option<String>,
+    pub voice: String,
+    pub output: Option<String>,
+    pub actor: String,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+impl Default for UsageEvent {
+    fn default() -> Self {
+        Self {
+            id: Self::generate_nanoid(),
+            workspace: std::string::String::default(),
+            kind: UsageKind::Synthesis,
+            characters: 0,
+            model: None,
+            voice: std::string::String::default(),
+            output: None,
+            actor: std::string::String::default(),
+            created_at: DateTime::now(),
+            updated_at: DateTime::now(),
+        }
+    }
+}
+
+impl Model for UsageEvent {}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct UsageSummary {
+    #[serde(rename = "_id")]
+    pub group: String,
+    pub characters: u64,
+    pub outputs: u64,
+    pub clones: u64,
+}
+
+impl UsageEvent {
+    pub async fn migrate() -> Result<CreateIndexesResult, MongooseError> {
+        Self::create_indexes(&[IndexModel::builder()
+            .keys(doc! { "workspace": 1, "created_at": 1 })
+            .build()])
+        .await
+    }
+
+    pub async fn summarize(
+        workspace: &str,
+        from: DateTime,
+        to: DateTime,
+        group: UsageGroup,
+    ) -> Result<Vec<UsageSummary>, MongooseError> {
+        let group_key: Document = match group {
+            UsageGroup::Day => doc! {
+                "$dateToString": { "format": "%Y-%m-%d", "date": "$created_at" }
+            },
+            UsageGroup::Voice => doc! { "$toString": "$voice" },
+            UsageGroup::Key => doc! { "$toString": "$actor" },
+        };
+        let pipeline = vec![
+            doc! { "$match": {
+                "workspace": workspace,
+                "created_at": { "$gte": from, "$lt": to },
+            }},
+            doc! { "$group": {
+                "_id": group_key,
+                "characters": { "$sum": "$characters" },
+                "outputs": { "$sum": {
+                    "$cond": [{ "$eq": ["$kind", UsageKind::Synthesis.to_string()] }, 1, 0]
+                }},
+                "clones": { "$sum": {
+                    "$cond": [{ "$eq": ["$kind", UsageKind::Clone.to_string()] }, 1, 0]
+                }},
+            }},
+            doc! { "$sort": { "_id": 1 } },
+        ];
+        Self::aggregate_raw(pipeline).await
+    }
+}
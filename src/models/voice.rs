@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use mongoose::{
     bson::{doc, DateTime},
     mongodb::{options::IndexOptions, results::CreateIndexesResult, IndexModel},
@@ -6,21 +8,25 @@ use mongoose::{
 };
 use serde::{Deserialize, Serialize};
 
+use crate::{audio::AudioMetadata, eleven_labs::VoiceSettings, models::drop_indexes};
+
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub enum VoiceStatus {
     Active,
     Draft,
     Training,
+    Failed,
     Deleted,
 }
 
-impl VoiceStatus {
-    pub fn to_string(&self) -> String {
+impl std::fmt::Display for VoiceStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            VoiceStatus::Active => "Active".to_string(),
-            VoiceStatus::Draft => "Draft".to_string(),
-            VoiceStatus::Training => "Training".to_string(),
-            VoiceStatus::Deleted => "Deleted".to_string(),
+            VoiceStatus::Active => write!(f, "Active"),
+            VoiceStatus::Draft => write!(f, "Draft"),
+            VoiceStatus::Training => write!(f, "Training"),
+            VoiceStatus::Failed => write!(f, "Failed"),
+            VoiceStatus::Deleted => write!(f, "Deleted"),
         }
     }
 }
@@ -29,10 +35,21 @@ impl VoiceStatus {
 pub struct Voice {
     #[serde(rename = "_id")]
     pub id: String,
+    pub workspace: String,
     pub name: String,
     pub status: VoiceStatus,
     pub description: Option<String>,
     pub eleven_labs_id: Option<String>,
+    #[serde(default)]
+    pub externally_managed: bool,
+    #[serde(default)]
+    pub actor: String,
+    pub sample_metadata: Option<AudioMetadata>,
+    pub labels: Option<HashMap<String, String>>,
+    #[serde(default)]
+    pub settings: VoiceSettings,
+    pub deleted_at: Option<DateTime>,
+    pub purge_after: Option<DateTime>,
     pub created_at: DateTime,
     pub updated_at: DateTime,
 }
@@ -41,10 +58,18 @@ impl Default for Voice {
     fn default() -> Self {
         Self {
             id: Self::generate_nanoid(),
+            workspace: std::string::String::default(),
             name: std::string::String::default(),
             status: VoiceStatus::Draft,
             description: None,
             eleven_labs_id: None,
+            externally_managed: false,
+            actor: std::string::String::default(),
+            sample_metadata: None,
+            labels: None,
+            settings: VoiceSettings::default(),
+            deleted_at: None,
+            purge_after: None,
             created_at: DateTime::now(),
             updated_at: DateTime::now(),
         }
@@ -55,20 +80,81 @@ impl Model for Voice {}
 
 impl Voice {
     pub async fn migrate() -> Result<CreateIndexesResult, MongooseError> {
-        Self::create_indexes(&vec![
+        // replaced by the workspace scoped indexes, the old unique name index would still reject
+        // names reused across workspaces
+        drop_indexes::<Self>(&["name_1", "status_1"]).await?;
+        Self::create_indexes(&[
+            // deleted voices keep their name for restoring, so only live voices are unique
             IndexModel::builder()
-                .keys(doc! { "name": 1 })
-                .options(IndexOptions::builder().unique(true).build())
+                .keys(doc! { "workspace": 1, "name": 1 })
+                .options(
+                    IndexOptions::builder()
+                        .unique(true)
+                        .partial_filter_expression(doc! {
+                            "status": { "$in": Self::live_statuses() }
+                        })
+                        .build(),
+                )
                 .build(),
             IndexModel::builder()
                 .keys(doc! { "eleven_labs_id": 1 })
                 .build(),
-            IndexModel::builder().keys(doc! { "status": 1 }).build(),
+            IndexModel::builder()
+                .keys(doc! { "workspace": 1, "status": 1 })
+                .build(),
+            IndexModel::builder()
+                .keys(doc! { "status": 1, "purge_after": 1 })
+                .build(),
         ])
         .await
     }
 
-    pub async fn active_voices_count() -> anyhow::Result<u64> {
-        Ok(Self::count(Some(doc! { "status": VoiceStatus::Active.to_string() })).await?)
+    pub fn live_statuses() -> Vec<String> {
+        [
+            VoiceStatus::Active,
+            VoiceStatus::Draft,
+            VoiceStatus::Training,
+            VoiceStatus::Failed,
+        ]
+        .iter()
+        .map(ToString::to_string)
+        .collect()
+    }
+
+    pub async fn read_in_workspace(workspace: &str, id: &str) -> Result<Self, MongooseError> {
+        Self::read(doc! { "_id": id, "workspace": workspace }).await
+    }
+
+    pub fn sample_key(&self) -> String {
+        format!("{}/{}.mp3", self.workspace, self.id)
+    }
+
+    pub async fn name_taken(workspace: &str, name: &str) -> bool {
+        Self::read(doc! {
+            "workspace": workspace,
+            "name": name,
+            "status": { "$in": Self::live_statuses() },
+        })
+        .await
+        .is_ok()
+    }
+
+    pub async fn purgeable() -> Result<Vec<Self>, MongooseError> {
+        Self::list(
+            Some(doc! {
+                "status": VoiceStatus::Deleted.to_string(),
+                "purge_after": { "$lte": DateTime::now() },
+            }),
+            None,
+        )
+        .await
+    }
+
+    pub async fn active_voices_count(workspace: &str) -> anyhow::Result<u64> {
+        Ok(Self::count(Some(doc! {
+            "workspace": workspace,
+            "status": VoiceStatus::Active.to_string(),
+        }))
+        .await?)
     }
 }
@@ -0,0 +1,62 @@
+use mongoose::{
+    bson::{doc, DateTime},
+    mongodb::{options::IndexOptions, results::CreateIndexesResult, IndexModel},
+    types::MongooseError,
+    Model,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::quota::Limits;
+
+// workspace that existing data is moved into, and that the bootstrap token uses by default
+pub const DEFAULT_WORKSPACE: &str = "default";
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Workspace {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub name: String,
+    pub voice_limit: u64,
+    #[serde(default)]
+    pub limits: Limits,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+impl Default for Workspace {
+    fn default() -> Self {
+        Self {
+            id: Self::generate_nanoid(),
+            name: std::string::String::default(),
+            voice_limit: 10,
+            limits: Limits::default(),
+            created_at: DateTime::now(),
+            updated_at: DateTime::now(),
+        }
+    }
+}
+
+impl Model for Workspace {}
+
+impl Workspace {
+    pub async fn migrate() -> Result<CreateIndexesResult, MongooseError> {
+        Self::create_indexes(&[IndexModel::builder()
+            .keys(doc! { "name": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build()])
+        .await
+    }
+
+    pub async fn ensure_default() -> Result<Self, MongooseError> {
+        if let Ok(workspace) = Self::read_by_id(DEFAULT_WORKSPACE).await {
+            return Ok(workspace);
+        }
+        Self {
+            id: DEFAULT_WORKSPACE.to_string(),
+            name: DEFAULT_WORKSPACE.to_string(),
+            ..Default::default()
+        }
+        .save()
+        .await
+    }
+}
@@ -0,0 +1,313 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::models::usage_counter::{UsageCounter, Window};
+
+// unset limits are unlimited
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub requests_per_minute: Option<u64>,
+    pub daily_character_limit: Option<u64>,
+    pub monthly_character_limit: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct QuotaSubject {
+    // e.g. key:{id} or workspace:{id}
+    pub subject: String,
+    pub limits: Limits,
+}
+
+#[derive(Debug, Clone)]
+pub struct Quota {
+    pub subject: String,
+    pub window: Window,
+    pub limit: u64,
+    pub remaining: u64,
+    // seconds until the window resets
+    pub reset_after: u64,
+    // the usage counter that was charged, for handing usage back
+    pub counter: String,
+}
+
+// where usage is counted, so the all or nothing logic does not depend on the database
+trait Counters {
+    // the id of the subject's counter for the window and its count after adding `amount`
+    async fn increment(
+        &self,
+        subject: &str,
+        window: Window,
+        amount: i64,
+    ) -> anyhow::Result<(String, i64)>;
+
+    // adds `amount` to a counter that was charged, not to the current window's
+    async fn adjust(&self, counter: &str, amount: i64) -> anyhow::Result<()>;
+}
+
+struct UsageCounters;
+
+impl Counters for UsageCounters {
+    async fn increment(
+        &self,
+        subject: &str,
+        window: Window,
+        amount: i64,
+    ) -> anyhow::Result<(String, i64)> {
+        let counter = UsageCounter::increment(subject, window, amount).await?;
+        Ok((counter.id, counter.count))
+    }
+
+    async fn adjust(&self, counter: &str, amount: i64) -> anyhow::Result<()> {
+        UsageCounter::adjust(counter, amount).await
+    }
+}
+
+async fn consume(
+    counters: &impl Counters,
+    subject: &str,
+    window: Window,
+    amount: u64,
+    limit: u64,
+) -> anyhow::Result<Result<Quota, Quota>> {
+    let amount = i64::try_from(amount)?;
+    let (counter, count) = counters.increment(subject, window, amount).await?;
+    let used = u64::try_from(count.max(0))?;
+    let (_, end) = window.bounds(Utc::now());
+    let reset_after = u64::try_from((end - Utc::now().timestamp_millis()).max(0) / 1000)? + 1;
+    if used > limit {
+        // hand the usage back so rejected requests do not eat into the quota
+        counters.adjust(&counter, -amount).await?;
+        return Ok(Err(Quota {
+            subject: subject.to_string(),
+            window,
+            limit,
+            remaining: 0,
+            reset_after,
+            counter,
+        }));
+    }
+    Ok(Ok(Quota {
+        subject: subject.to_string(),
+        window,
+        limit,
+        remaining: limit - used,
+        reset_after,
+        counter,
+    }))
+}
+
+// consumes `amount` from every subject's limit in the window, all or nothing
+async fn consume_all(
+    counters: &impl Counters,
+    subjects: &[QuotaSubject],
+    window: Window,
+    amount: u64,
+    limit: fn(&Limits) -> Option<u64>,
+) -> anyhow::Result<Result<Vec<Quota>, Quota>> {
+    let mut consumed: Vec<Quota> = vec![];
+    for subject in subjects {
+        let Some(limit) = limit(&subject.limits) else {
+            continue;
+        };
+        match consume(counters, &subject.subject, window, amount, limit).await? {
+            Ok(quota) => consumed.push(quota),
+            Err(exceeded) => {
+                for quota in &consumed {
+                    counters
+                        .adjust(&quota.counter, -i64::try_from(amount)?)
+                        .await?;
+                }
+                return Ok(Err(exceeded));
+            }
+        }
+    }
+    Ok(Ok(consumed))
+}
+
+pub async fn consume_request(subjects: &[QuotaSubject]) -> anyhow::Result<Result<(), Quota>> {
+    let consumed = consume_all(&UsageCounters, subjects, Window::Minute, 1, |limits| {
+        limits.requests_per_minute
+    })
+    .await?;
+    Ok(consumed.map(|_| ()))
+}
+
+// returns every character quota charged, nothing when none are limited
+pub async fn consume_characters(
+    subjects: &[QuotaSubject],
+    characters: u64,
+) -> anyhow::Result<Result<Vec<Quota>, Quota>> {
+    let daily = match consume_all(
+        &UsageCounters,
+        subjects,
+        Window::Day,
+        characters,
+        |limits| limits.daily_character_limit,
+    )
+    .await?
+    {
+        Ok(daily) => daily,
+        Err(exceeded) => return Ok(Err(exceeded)),
+    };
+    let monthly = match consume_all(
+        &UsageCounters,
+        subjects,
+        Window::Month,
+        characters,
+        |limits| limits.monthly_character_limit,
+    )
+    .await?
+    {
+        Ok(monthly) => monthly,
+        Err(exceeded) => {
+            for quota in &daily {
+                UsageCounters
+                    .adjust(&quota.counter, -i64::try_from(characters)?)
+                    .await?;
+            }
+            return Ok(Err(exceeded));
+        }
+    };
+    Ok(Ok(daily.into_iter().chain(monthly).collect()))
+}
+
+pub fn tightest(quotas: &[Quota]) -> Option<&Quota> {
+    quotas.iter().min_by_key(|quota| quota.remaining)
+}
+
+// hands characters back to the counters they were charged to, once a window has ended its
+// counter is gone and there is nothing left to refund
+pub async fn refund_characters(counters: &[String], characters: u64) -> anyhow::Result<()> {
+    let amount = i64::try_from(characters)?;
+    for counter in counters {
+        UsageCounter::adjust(counter, -amount).await?;
+    }
+    Ok(())
+}
+
+// charges the same counters again, e.g. when a refunded output is redriven
+pub async fn recharge_characters(counters: &[String], characters: u64) -> anyhow::Result<()> {
+    let amount = i64::try_from(characters)?;
+    for counter in counters {
+        UsageCounter::adjust(counter, amount).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct MemoryCounters(Mutex<HashMap<String, i64>>);
+
+    impl Counters for MemoryCounters {
+        async fn increment(
+            &self,
+            subject: &str,
+            window: Window,
+            amount: i64,
+        ) -> anyhow::Result<(String, i64)> {
+            let id = format!("{subject}:{window}");
+            let mut counts = self.0.lock().unwrap();
+            let count = counts.entry(id.clone()).or_default();
+            *count += amount;
+            Ok((id, *count))
+        }
+
+        async fn adjust(&self, counter: &str, amount: i64) -> anyhow::Result<()> {
+            if let Some(count) = self.0.lock().unwrap().get_mut(counter) {
+                *count += amount;
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn consume_all_charges_only_limited_subjects_up_to_their_limit() {
+        let counters = MemoryCounters::default();
+        let subjects = [
+            QuotaSubject {
+                subject: "key:a".to_string(),
+                limits: Limits {
+                    requests_per_minute: Some(5),
+                    ..Default::default()
+                },
+            },
+            QuotaSubject {
+                subject: "workspace:w".to_string(),
+                limits: Limits::default(),
+            },
+        ];
+        let limit = |limits: &Limits| limits.requests_per_minute;
+        let consumed = consume_all(&counters, &subjects, Window::Minute, 5, limit)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(consumed.len(), 1);
+        assert_eq!(
+            (consumed[0].subject.as_str(), consumed[0].remaining),
+            ("key:a", 0)
+        );
+        assert_eq!(consumed[0].counter, "key:a:Minute");
+        let exceeded = consume_all(&counters, &subjects, Window::Minute, 1, limit)
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(
+            (exceeded.subject.as_str(), exceeded.remaining),
+            ("key:a", 0)
+        );
+        let counts = counters.0.into_inner().unwrap();
+        assert_eq!(counts, HashMap::from([("key:a:Minute".to_string(), 5)]));
+    }
+
+    #[tokio::test]
+    async fn consume_all_hands_back_earlier_charges_when_a_later_limit_is_exceeded() {
+        let counters = MemoryCounters::default();
+        let subjects = [("key:a", 10), ("workspace:w", 2)].map(|(subject, limit)| QuotaSubject {
+            subject: subject.to_string(),
+            limits: Limits {
+                requests_per_minute: Some(limit),
+                ..Default::default()
+            },
+        });
+        let exceeded = consume_all(&counters, &subjects, Window::Minute, 3, |limits| {
+            limits.requests_per_minute
+        })
+        .await
+        .unwrap()
+        .unwrap_err();
+        assert_eq!(
+            (exceeded.subject.as_str(), exceeded.limit),
+            ("workspace:w", 2)
+        );
+        let counts = counters.0.into_inner().unwrap();
+        assert!(counts.values().all(|count| *count == 0), "{counts:?}");
+    }
+
+    #[test]
+    fn tightest_is_the_quota_with_the_least_remaining() {
+        let daily = Quota {
+            subject: "key:a".to_string(),
+            window: Window::Day,
+            limit: 100,
+            remaining: 40,
+            reset_after: 1,
+            counter: "key:a:Day".to_string(),
+        };
+        let monthly = Quota {
+            window: Window::Month,
+            remaining: 10,
+            counter: "key:a:Month".to_string(),
+            ..daily.clone()
+        };
+        assert_eq!(
+            tightest(&[daily, monthly]).map(|quota| quota.window),
+            Some(Window::Month)
+        );
+        assert!(tightest(&[]).is_none());
+    }
+}
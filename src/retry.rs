@@ -0,0 +1,166 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // including the first attempt
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    // full jitter, a random delay up to the capped exponential backoff
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(2_u32.saturating_pow(attempt));
+        let capped = exponential.min(self.max_delay);
+        let millis = u64::try_from(capped.as_millis()).unwrap_or(u64::MAX);
+        Duration::from_millis(rand::thread_rng().gen_range(0..=millis))
+    }
+
+    // how long to wait before the next attempt, if there should be one
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        if attempt + 1 >= self.max_attempts {
+            return None;
+        }
+        match retry_after {
+            // not worth holding the invocation open for, the caller should defer instead
+            Some(retry_after) if retry_after > self.max_delay => None,
+            Some(retry_after) => Some(retry_after),
+            None => Some(self.backoff(attempt)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BreakerState {
+    failures: u32,
+    opened_at: Option<Instant>,
+}
+
+impl BreakerState {
+    pub const fn closed() -> Self {
+        Self {
+            failures: 0,
+            opened_at: None,
+        }
+    }
+}
+
+// the state lives in a static so it is shared across warm invocations
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    pub threshold: u32,
+    pub cooldown: Duration,
+    state: &'static Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub const fn new(
+        state: &'static Mutex<BreakerState>,
+        threshold: u32,
+        cooldown: Duration,
+    ) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            state,
+        }
+    }
+
+    // time left while open, once the cooldown passes calls are let through again as a trial
+    pub fn open_for(&self) -> Option<Duration> {
+        let state = self.state.lock().ok()?;
+        let opened_at = state.opened_at?;
+        self.cooldown
+            .checked_sub(opened_at.elapsed())
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    pub fn record_success(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            if state.opened_at.is_some() {
+                tracing::info!("circuit closed");
+            }
+            *state = BreakerState::closed();
+        }
+    }
+
+    // a failed trial call re-opens the circuit straight away
+    pub fn record_failure(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.failures = state.failures.saturating_add(1);
+            if state.failures >= self.threshold {
+                tracing::warn!("circuit opened after {} failures", state.failures);
+                state.opened_at = Some(Instant::now());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: RetryPolicy = RetryPolicy {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(100),
+        max_delay: Duration::from_secs(1),
+    };
+
+    #[test]
+    fn backoff_never_exceeds_the_cap() {
+        assert!(POLICY.backoff(0) <= POLICY.base_delay);
+        for attempt in 0..40 {
+            assert!(POLICY.backoff(attempt) <= POLICY.max_delay);
+        }
+    }
+
+    #[test]
+    fn delay_stops_at_the_last_attempt() {
+        assert!(POLICY.delay(0, None).is_some());
+        assert!(POLICY.delay(1, None).is_some());
+        assert_eq!(POLICY.delay(2, None), None);
+    }
+
+    #[test]
+    fn delay_honours_retry_after_up_to_the_cap() {
+        let retry_after = Duration::from_millis(500);
+        assert_eq!(POLICY.delay(0, Some(retry_after)), Some(retry_after));
+        // longer waits are left to the caller to defer
+        assert_eq!(POLICY.delay(0, Some(Duration::from_secs(5))), None);
+    }
+
+    #[test]
+    fn breaker_opens_at_the_threshold_and_closes_on_success() {
+        static STATE: Mutex<BreakerState> = Mutex::new(BreakerState::closed());
+        let breaker = CircuitBreaker::new(&STATE, 2, Duration::from_secs(60));
+        breaker.record_failure();
+        assert_eq!(breaker.open_for(), None);
+        breaker.record_failure();
+        assert!(breaker
+            .open_for()
+            .is_some_and(|open_for| open_for <= breaker.cooldown));
+        breaker.record_success();
+        assert_eq!(breaker.open_for(), None);
+    }
+
+    #[test]
+    fn breaker_reopens_when_the_trial_after_the_cooldown_fails() {
+        static STATE: Mutex<BreakerState> = Mutex::new(BreakerState::closed());
+        let cooled_down = CircuitBreaker::new(&STATE, 2, Duration::ZERO);
+        cooled_down.record_failure();
+        cooled_down.record_failure();
+        assert_eq!(cooled_down.open_for(), None);
+        // the state is shared, so one more failure opens it without counting up again
+        let breaker = CircuitBreaker::new(&STATE, 2, Duration::from_secs(60));
+        breaker.record_failure();
+        assert!(breaker.open_for().is_some());
+    }
+}
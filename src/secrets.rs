@@ -0,0 +1,151 @@
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::Result;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::env::SecretsSection;
+
+// the shape of a local secrets json, either list can be left out
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Secrets {
+    #[serde(default)]
+    pub authentication_tokens: Vec<String>,
+    #[serde(default)]
+    pub eleven_labs_api_keys: Vec<String>,
+}
+
+// one value per line, the way mounted secrets usually look
+fn read_lines(path: &str) -> Result<Vec<String>> {
+    let raw = std::fs::read_to_string(path)?;
+    let lines = raw
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(ToString::to_string)
+        .collect();
+    Ok(lines)
+}
+
+fn merge(values: &mut Vec<String>, more: &[String]) {
+    for value in more {
+        if !values.contains(value) {
+            values.push(value.to_string());
+        }
+    }
+}
+
+// hashed first, so neither the contents nor the length of a token can be timed
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (Sha256::digest(a.as_bytes()), Sha256::digest(b.as_bytes()));
+    a.iter()
+        .zip(b.iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+// every listed value is valid at once, so old and new secrets overlap during a rotation
+#[derive(Debug)]
+pub struct SecretStore {
+    section: SecretsSection,
+    loaded: RwLock<Secrets>,
+}
+
+impl SecretStore {
+    pub fn load(section: &SecretsSection) -> Result<Arc<Self>> {
+        let secrets = Self::read(section)?;
+        let store = Arc::new(Self {
+            section: section.clone(),
+            loaded: RwLock::new(secrets),
+        });
+        let has_files = section.secrets_file.is_some()
+            || section.authentication_tokens_file.is_some()
+            || section.eleven_labs_api_keys_file.is_some();
+        if has_files && section.reload_secs > 0 {
+            Self::reload_every(&store, Duration::from_secs(section.reload_secs));
+        }
+        Ok(store)
+    }
+
+    // files are read again in the background, requests only ever read what is loaded
+    fn reload_every(store: &Arc<Self>, period: Duration) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            tracing::warn!("no runtime to reload secrets on, they are only read once");
+            return;
+        };
+        let store = Arc::downgrade(store);
+        runtime.spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            // the first tick completes immediately, the secrets were just read
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let Some(store) = store.upgrade() else {
+                    return;
+                };
+                store.reload().await;
+            }
+        });
+    }
+
+    // a failed reload keeps the last secrets that loaded
+    async fn reload(&self) {
+        let section = self.section.clone();
+        let secrets = match tokio::task::spawn_blocking(move || Self::read(&section)).await {
+            Ok(secrets) => secrets,
+            Err(err) => Err(err.into()),
+        };
+        match (secrets, self.loaded.write()) {
+            (Ok(secrets), Ok(mut loaded)) => *loaded = secrets,
+            (Err(err), _) => tracing::error!("error reloading secrets: {err:?}"),
+            (_, Err(err)) => tracing::error!("error storing reloaded secrets: {err:?}"),
+        }
+    }
+
+    // files come first, the first eleven labs key is the one used until it is rejected
+    fn read(section: &SecretsSection) -> Result<Secrets> {
+        let mut secrets = match &section.secrets_file {
+            Some(path) => serde_json::from_str::<Secrets>(&std::fs::read_to_string(path)?)?,
+            None => Secrets::default(),
+        };
+        if let Some(path) = &section.authentication_tokens_file {
+            merge(&mut secrets.authentication_tokens, &read_lines(path)?);
+        }
+        if let Some(path) = &section.eleven_labs_api_keys_file {
+            merge(&mut secrets.eleven_labs_api_keys, &read_lines(path)?);
+        }
+        merge(
+            &mut secrets.authentication_tokens,
+            &section.authentication_tokens,
+        );
+        merge(
+            &mut secrets.eleven_labs_api_keys,
+            &section.eleven_labs_api_keys,
+        );
+        Ok(secrets)
+    }
+
+    pub fn current(&self) -> Secrets {
+        self.loaded
+            .read()
+            .map(|loaded| loaded.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn is_authentication_token(&self, token: &str) -> bool {
+        // every token is compared, so the time taken does not tell which one matched
+        self.current()
+            .authentication_tokens
+            .iter()
+            .fold(false, |matched, valid| {
+                constant_time_eq(valid, token) | matched
+            })
+    }
+
+    pub fn eleven_labs_api_keys(&self) -> Vec<String> {
+        self.current().eleven_labs_api_keys
+    }
+}
@@ -0,0 +1,142 @@
+use anyhow::Result;
+use aws_lambda_events::event::sqs::SqsMessage;
+use mongoose::{
+    bson::{doc, to_bson},
+    Model,
+};
+
+use super::{receive_count, Outcome};
+use crate::{
+    audio::AudioMetadata,
+    aws::s3::Client,
+    eleven_labs::{ElevenLabs, MODEL_ID},
+    models::{
+        audit_event::{AuditAction, AuditEvent},
+        insert_once,
+        output::{Output, OutputStatus},
+        usage_event::{UsageEvent, UsageKind},
+        voice::Voice,
+    },
+    types::{CreateOutputFifoMessage, Envelope},
+};
+
+pub const NAME: &str = "create-output";
+
+pub async fn process(
+    message: SqsMessage,
+    voice_api: &ElevenLabs,
+    outputs_bucket: &Client,
+    max_receive_count: u32,
+) -> Result<Outcome> {
+    let outcome = match synthesize(&message, voice_api, outputs_bucket).await {
+        Ok(outcome) => outcome,
+        Err(err) => Outcome::from_error(&err),
+    };
+    // the queue dead letters a message once its last receive fails
+    let last_retry = matches!(outcome, Outcome::Retry(_) | Outcome::RetryAfter(..))
+        && receive_count(&message) >= max_receive_count;
+    if matches!(outcome, Outcome::DeadLetter(_)) || last_retry {
+        if let Err(err) = refund(&message).await {
+            tracing::error!("[{NAME}] error refunding characters: {err:?}");
+        }
+    }
+    Ok(outcome)
+}
+
+// the output will not be made, so its characters go back to the quotas
+async fn refund(message: &SqsMessage) -> Result<()> {
+    let body = message.body.as_deref().unwrap_or_default();
+    let envelope = Envelope::<CreateOutputFifoMessage>::open(body)?;
+    let output = Output::read_by_id(&envelope.payload.output_id).await?;
+    output.refund_characters().await
+}
+
+async fn synthesize(
+    message: &SqsMessage,
+    voice_api: &ElevenLabs,
+    outputs_bucket: &Client,
+) -> Result<Outcome> {
+    // the messages go back to the queue until the provider recovers
+    if let Some(open_for) = voice_api.circuit_open() {
+        let cooldown = voice_api.breaker.cooldown;
+        return Ok(Outcome::circuit_open(
+            cooldown,
+            open_for,
+            receive_count(message),
+        ));
+    }
+    let Some(body) = &message.body else {
+        return Ok(Outcome::DeadLetter("message has no body".to_string()));
+    };
+    let envelope = Envelope::<CreateOutputFifoMessage>::open(body)?;
+    tracing::info!(
+        "[{NAME}] {} correlation {} attempt {} enqueued by {} at {}",
+        envelope.payload.output_id,
+        envelope.correlation_id,
+        envelope.attempt,
+        envelope.actor,
+        envelope.enqueued_at
+    );
+    let data = &envelope.payload;
+    let output = Output::read_by_id(&data.output_id).await?;
+    if output.status == OutputStatus::Done {
+        return Ok(Outcome::Skipped(format!(
+            "output {} is already done",
+            output.id
+        )));
+    }
+    let voice = Voice::read_by_id(&output.voice).await?;
+    let Some(eleven_labs_id) = &voice.eleven_labs_id else {
+        return Ok(Outcome::DeadLetter(format!(
+            "voice {} has no eleven labs id",
+            voice.id
+        )));
+    };
+    let bytes = voice_api
+        .text_to_speech(eleven_labs_id, &output.text, &voice.settings)
+        .await?;
+    outputs_bucket
+        .put_object(&output.object_key(), bytes.to_vec())
+        .await?;
+    let metadata = match AudioMetadata::from_mp3(&bytes) {
+        Ok(metadata) => Some(metadata),
+        Err(err) => {
+            tracing::warn!("error reading output {} audio: {err:?}", output.id);
+            None
+        }
+    };
+    // recorded before the status changes, a retry after a failed update skips neither
+    let usage = UsageEvent {
+        id: format!("synthesis:{}", output.id),
+        workspace: output.workspace.to_string(),
+        kind: UsageKind::Synthesis,
+        characters: output.characters()?,
+        model: Some(MODEL_ID.to_string()),
+        voice: voice.id.to_string(),
+        output: Some(output.id.to_string()),
+        actor: output.actor.to_string(),
+        ..Default::default()
+    };
+    insert_once(&usage).await?;
+    AuditEvent::record_once(
+        format!("output-created:{}", output.id),
+        &output.workspace,
+        &format!("worker:{NAME}"),
+        AuditAction::OutputCreated,
+        format!("output:{}", output.id),
+        envelope.request_id.as_deref(),
+    )
+    .await?;
+    let updated = Output::update(
+        doc! { "_id": output.id },
+        doc! {
+            "status": OutputStatus::Done.to_string(),
+            "metadata": to_bson(&metadata)?,
+        },
+    )
+    .await?;
+    // TODO: send server side event of process complete
+    tracing::info!("OUTPUT: {:?}", updated);
+    tracing::info!("USAGE: {:?}", usage);
+    Ok(Outcome::Processed)
+}
@@ -0,0 +1,527 @@
+use std::{future::Future, time::Duration};
+
+use anyhow::Result;
+use aws_lambda_events::event::sqs::{BatchItemFailure, SqsBatchResponse, SqsMessage};
+use chrono::Utc;
+use futures::{stream, StreamExt};
+use mongoose::types::MongooseError;
+use serde_json::json;
+use tokio::sync::watch;
+
+use crate::{
+    aws::sqs::{FifoQueue, ReceiveOptions, ReceivedMessage},
+    eleven_labs::ProviderError,
+    types::EnvelopeError,
+};
+
+pub mod create_output;
+pub mod train_sample;
+
+const MESSAGE_GROUP_ATTRIBUTE: &str = "MessageGroupId";
+const RECEIVE_COUNT_ATTRIBUTE: &str = "ApproximateReceiveCount";
+const METRICS_NAMESPACE: &str = "parrot";
+const RECEIVE_ERROR_DELAY: Duration = Duration::from_secs(5);
+// well inside the 12 hours sqs keeps a message in flight
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Processed,
+    // nothing to do, the message is acknowledged
+    Skipped(String),
+    // transient, the message goes back to the queue
+    Retry(String),
+    // transient, the message stays hidden on the queue for the delay before it is retried
+    RetryAfter(String, Duration),
+    // permanent, retrying cannot change the result
+    DeadLetter(String),
+}
+
+impl Outcome {
+    // errors are retried unless they can only fail the same way again
+    pub fn from_error(err: &anyhow::Error) -> Self {
+        let permanent = match err.downcast_ref::<ProviderError>() {
+            Some(err) => matches!(
+                err,
+                ProviderError::VoiceNotFound { .. } | ProviderError::Validation { .. }
+            ),
+            None => {
+                err.downcast_ref::<serde_json::Error>().is_some()
+                    || err.downcast_ref::<EnvelopeError>().is_some()
+                    || matches!(
+                        err.downcast_ref::<MongooseError>(),
+                        Some(MongooseError::NotFound(_))
+                    )
+            }
+        };
+        if permanent {
+            return Self::DeadLetter(err.to_string());
+        }
+        Self::Retry(err.to_string())
+    }
+
+    // every receive counts toward the queue's dead letter limit, so each retry while the
+    // provider is out waits longer and the receives outlast the outage instead of one cooldown
+    pub fn circuit_open(cooldown: Duration, open_for: Duration, receive_count: u32) -> Self {
+        let backoff = 4u32.saturating_pow(receive_count.saturating_sub(1));
+        let delay = cooldown
+            .saturating_mul(backoff)
+            .max(open_for)
+            .min(MAX_RETRY_DELAY);
+        Self::RetryAfter(
+            format!("eleven labs circuit is open for {open_for:?}"),
+            delay,
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct OutcomeCounts {
+    pub processed: u64,
+    pub skipped: u64,
+    pub retried: u64,
+    pub dead_lettered: u64,
+}
+
+impl OutcomeCounts {
+    fn add(&mut self, outcome: &Outcome) {
+        match outcome {
+            Outcome::Processed => self.processed += 1,
+            Outcome::Skipped(_) => self.skipped += 1,
+            Outcome::Retry(_) | Outcome::RetryAfter(..) => self.retried += 1,
+            Outcome::DeadLetter(_) => self.dead_lettered += 1,
+        }
+    }
+}
+
+fn message_group(record: &SqsMessage) -> String {
+    record
+        .attributes
+        .get(MESSAGE_GROUP_ATTRIBUTE)
+        .or(record.message_id.as_ref())
+        .map_or_else(String::default, ToString::to_string)
+}
+
+// including the current receive
+pub fn receive_count(record: &SqsMessage) -> u32 {
+    record
+        .attributes
+        .get(RECEIVE_COUNT_ATTRIBUTE)
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(1)
+}
+
+// records keep their batch order within a group, fifo only orders within a group anyway
+pub fn message_groups(records: Vec<SqsMessage>) -> Vec<Vec<SqsMessage>> {
+    let mut groups: Vec<(String, Vec<SqsMessage>)> = vec![];
+    for record in records {
+        let group = message_group(&record);
+        match groups.iter_mut().find(|(id, _)| id == &group) {
+            Some((_, records)) => records.push(record),
+            None => groups.push((group, vec![record])),
+        }
+    }
+    groups.into_iter().map(|(_, records)| records).collect()
+}
+
+// the shape lambda hands to the handlers, so polled messages run through the same code
+impl From<ReceivedMessage> for SqsMessage {
+    fn from(message: ReceivedMessage) -> Self {
+        Self {
+            message_id: Some(message.message_id),
+            receipt_handle: Some(message.receipt_handle),
+            body: Some(message.body),
+            attributes: message.attributes,
+            ..Default::default()
+        }
+    }
+}
+
+pub struct Worker {
+    pub name: &'static str,
+    pub concurrency: usize,
+    // the queue the records are received from
+    pub queue: FifoQueue,
+    pub dead_letter_queue: Option<FifoQueue>,
+}
+
+impl Worker {
+    pub async fn new(
+        name: &'static str,
+        concurrency: usize,
+        queue_url: String,
+        dead_letter_queue_url: Option<String>,
+    ) -> Self {
+        let dead_letter_queue = match dead_letter_queue_url {
+            Some(queue_url) => Some(FifoQueue::new(queue_url).await),
+            None => None,
+        };
+        Self {
+            name,
+            concurrency: concurrency.max(1),
+            queue: FifoQueue::new(queue_url).await,
+            dead_letter_queue,
+        }
+    }
+
+    // runs up to `concurrency` message groups at once, each group one record at a time,
+    // and reports every record that should be received again
+    pub async fn run<F, Fut>(&self, records: Vec<SqsMessage>, process: F) -> SqsBatchResponse
+    where
+        F: Fn(SqsMessage) -> Fut,
+        Fut: Future<Output = Result<Outcome>>,
+    {
+        let process = &process;
+        let groups = stream::iter(message_groups(records))
+            .map(|group| async move {
+                let mut outcomes = vec![];
+                let mut blocked = false;
+                for record in group {
+                    // later records in the group must not overtake one going back to the queue
+                    if blocked {
+                        let outcome =
+                            Outcome::Retry("an earlier message in the group failed".into());
+                        outcomes.push((record, outcome, true));
+                        continue;
+                    }
+                    let outcome = match process(record.clone()).await {
+                        Ok(outcome) => outcome,
+                        Err(err) => Outcome::from_error(&err),
+                    };
+                    let failed = match &outcome {
+                        Outcome::Processed | Outcome::Skipped(_) => false,
+                        Outcome::Retry(_) | Outcome::RetryAfter(..) => true,
+                        Outcome::DeadLetter(_) => !self.dead_letter(&record).await,
+                    };
+                    blocked = failed;
+                    outcomes.push((record, outcome, failed));
+                }
+                outcomes
+            })
+            .buffer_unordered(self.concurrency)
+            .collect::<Vec<_>>()
+            .await;
+        let mut counts = OutcomeCounts::default();
+        let mut batch_item_failures = vec![];
+        for (record, outcome, failed) in groups.into_iter().flatten() {
+            let id = record.message_id.clone().unwrap_or_default();
+            self.log(&id, &outcome);
+            counts.add(&outcome);
+            // after the batch, so a poll extending the batch's visibility cannot undo it
+            if let Outcome::RetryAfter(_, delay) = &outcome {
+                self.delay(&record, *delay).await;
+            }
+            if failed {
+                batch_item_failures.push(BatchItemFailure {
+                    item_identifier: id,
+                });
+            }
+        }
+        self.emit_metrics(&counts);
+        SqsBatchResponse {
+            batch_item_failures,
+        }
+    }
+
+    // for running outside of lambda, a batch in flight always finishes and is acked before stopping
+    pub async fn poll<F, Fut>(
+        &self,
+        visibility_timeout: Duration,
+        process: F,
+        mut shutdown: watch::Receiver<bool>,
+    ) where
+        F: Fn(SqsMessage) -> Fut,
+        Fut: Future<Output = Result<Outcome>>,
+    {
+        let (name, queue) = (self.name, &self.queue);
+        let options = ReceiveOptions {
+            visibility_timeout: Some(visibility_timeout),
+            ..Default::default()
+        };
+        tracing::info!("[{name}] polling {}", queue.queue_url);
+        while !*shutdown.borrow() {
+            let received = tokio::select! {
+                received = queue.receive_messages(&options) => received,
+                _ = shutdown.changed() => break,
+            };
+            let messages = match received {
+                Ok(messages) => messages,
+                Err(err) => {
+                    tracing::error!("[{name}] error receiving messages: {err:?}");
+                    tokio::time::sleep(RECEIVE_ERROR_DELAY).await;
+                    continue;
+                }
+            };
+            if messages.is_empty() {
+                continue;
+            }
+            let records = messages.iter().cloned().map(SqsMessage::from).collect();
+            let run = self.run(records, &process);
+            tokio::pin!(run);
+            // long synthesis jobs would otherwise reappear to other consumers mid batch
+            let mut extend = tokio::time::interval(visibility_timeout / 2);
+            extend.tick().await;
+            let response = loop {
+                tokio::select! {
+                    response = &mut run => break response,
+                    _ = extend.tick() => self.extend(&messages, visibility_timeout).await,
+                }
+            };
+            let failed = response
+                .batch_item_failures
+                .into_iter()
+                .map(|failure| failure.item_identifier)
+                .collect::<Vec<_>>();
+            // failures are left to reappear once their visibility runs out
+            for message in messages
+                .iter()
+                .filter(|message| !failed.contains(&message.message_id))
+            {
+                if let Err(err) = queue.delete_message(message).await {
+                    tracing::error!("[{name}] error acking {}: {err:?}", message.message_id);
+                }
+            }
+        }
+        tracing::info!("[{name}] stopped polling");
+    }
+
+    async fn extend(&self, messages: &[ReceivedMessage], visibility_timeout: Duration) {
+        for message in messages {
+            if let Err(err) = self
+                .queue
+                .change_visibility(&message.receipt_handle, visibility_timeout)
+                .await
+            {
+                let id = &message.message_id;
+                tracing::warn!("[{}] error extending {id}: {err:?}", self.name);
+            }
+        }
+    }
+
+    // on failure the record still comes back, just after the queue's own visibility timeout
+    async fn delay(&self, record: &SqsMessage, delay: Duration) {
+        let receipt_handle = record.receipt_handle.as_deref().unwrap_or_default();
+        if let Err(err) = self.queue.change_visibility(receipt_handle, delay).await {
+            let id = record.message_id.as_deref().unwrap_or_default();
+            tracing::warn!("[{}] error delaying {id}: {err:?}", self.name);
+        }
+    }
+
+    // without a dead letter queue the message is failed, and the queue's redrive policy moves it
+    async fn dead_letter(&self, record: &SqsMessage) -> bool {
+        let Some(queue) = &self.dead_letter_queue else {
+            return false;
+        };
+        let body = record.body.as_deref().unwrap_or_default();
+        let id = record.message_id.as_deref().unwrap_or_default();
+        match queue
+            .send_raw_fifo_message(body, &message_group(record), id)
+            .await
+        {
+            Ok(_) => true,
+            Err(err) => {
+                tracing::error!("[{}] error dead lettering message {id}: {err:?}", self.name);
+                false
+            }
+        }
+    }
+
+    fn log(&self, id: &str, outcome: &Outcome) {
+        let name = self.name;
+        match outcome {
+            Outcome::Processed => tracing::info!("[{name}] PROCESSED {id}"),
+            Outcome::Skipped(reason) => tracing::info!("[{name}] SKIPPED {id}: {reason}"),
+            Outcome::Retry(reason) => tracing::warn!("[{name}] RETRY {id}: {reason}"),
+            Outcome::RetryAfter(reason, delay) => {
+                tracing::warn!("[{name}] RETRY {id} in {delay:?}: {reason}");
+            }
+            Outcome::DeadLetter(reason) => tracing::error!("[{name}] DEAD LETTER {id}: {reason}"),
+        }
+    }
+
+    // cloudwatch embedded metric format, picked up from stdout without any extra calls
+    fn emit_metrics(&self, counts: &OutcomeCounts) {
+        let metrics = ["Processed", "Skipped", "Retried", "DeadLettered"]
+            .iter()
+            .map(|name| json!({ "Name": name, "Unit": "Count" }))
+            .collect::<Vec<_>>();
+        let line = json!({
+            "_aws": {
+                "Timestamp": Utc::now().timestamp_millis(),
+                "CloudWatchMetrics": [{
+                    "Namespace": METRICS_NAMESPACE,
+                    "Dimensions": [["worker"]],
+                    "Metrics": metrics,
+                }],
+            },
+            "worker": self.name,
+            "Processed": counts.processed,
+            "Skipped": counts.skipped,
+            "Retried": counts.retried,
+            "DeadLettered": counts.dead_lettered,
+        });
+        println!("{line}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use aws_sdk_sqs as sqs;
+
+    use super::*;
+    use crate::types::MessageType;
+
+    #[test]
+    fn from_error_dead_letters_only_what_cannot_succeed_on_a_retry() {
+        let permanent: [anyhow::Error; 5] = [
+            ProviderError::VoiceNotFound {
+                message: "voice".to_string(),
+            }
+            .into(),
+            ProviderError::Validation {
+                message: "text".to_string(),
+            }
+            .into(),
+            serde_json::from_str::<serde_json::Value>("{")
+                .unwrap_err()
+                .into(),
+            EnvelopeError::UnsupportedVersion {
+                message_type: MessageType::CreateOutput,
+                version: 2,
+                expected: 1,
+            }
+            .into(),
+            MongooseError::NotFound("outputs".to_string()).into(),
+        ];
+        for err in &permanent {
+            assert!(
+                matches!(Outcome::from_error(err), Outcome::DeadLetter(_)),
+                "{err}"
+            );
+        }
+        let transient: [anyhow::Error; 4] = [
+            ProviderError::ServerError {
+                status: 503,
+                message: "unavailable".to_string(),
+            }
+            .into(),
+            ProviderError::CircuitOpen { retry_after: 1 }.into(),
+            MongooseError::Insert("outputs".to_string()).into(),
+            anyhow::anyhow!("connection reset"),
+        ];
+        for err in &transient {
+            assert!(
+                matches!(Outcome::from_error(err), Outcome::Retry(_)),
+                "{err}"
+            );
+        }
+    }
+
+    #[test]
+    fn circuit_open_holds_messages_longer_on_every_receive() {
+        let cooldown = Duration::from_secs(30);
+        let delays = [1, 2, 3, 20].map(|receive_count| {
+            match Outcome::circuit_open(cooldown, Duration::from_secs(10), receive_count) {
+                Outcome::RetryAfter(_, delay) => delay,
+                outcome => panic!("unexpected {outcome:?}"),
+            }
+        });
+        assert_eq!(
+            delays,
+            [cooldown, cooldown * 4, cooldown * 16, MAX_RETRY_DELAY]
+        );
+    }
+
+    #[test]
+    fn receive_count_reads_the_attribute_and_defaults_to_the_first_receive() {
+        let mut message = SqsMessage::default();
+        assert_eq!(receive_count(&message), 1);
+        message
+            .attributes
+            .insert(RECEIVE_COUNT_ATTRIBUTE.to_string(), "3".to_string());
+        assert_eq!(receive_count(&message), 3);
+    }
+
+    #[test]
+    fn message_groups_keep_batch_order_within_each_group() {
+        let records = [
+            ("a1", Some("a")),
+            ("b1", Some("b")),
+            ("a2", Some("a")),
+            ("c1", None),
+        ]
+        .map(|(id, group)| SqsMessage {
+            message_id: Some(id.to_string()),
+            attributes: group
+                .map(|group| {
+                    HashMap::from([(MESSAGE_GROUP_ATTRIBUTE.to_string(), group.to_string())])
+                })
+                .unwrap_or_default(),
+            ..Default::default()
+        });
+        let groups = message_groups(records.to_vec());
+        let ids = groups
+            .iter()
+            .map(|group| {
+                group
+                    .iter()
+                    .filter_map(|record| record.message_id.as_deref())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [vec!["a1", "a2"], vec!["b1"], vec!["c1"]]);
+    }
+
+    #[tokio::test]
+    async fn run_holds_back_the_rest_of_a_group_after_a_failure() {
+        // the queue is only called to delay or dead letter, which none of these records do
+        let worker = Worker {
+            name: "test",
+            concurrency: 2,
+            queue: FifoQueue {
+                queue_url: String::default(),
+                client: sqs::Client::from_conf(sqs::Config::builder().build()),
+            },
+            dead_letter_queue: None,
+        };
+        let records = [
+            ("a1", "a"),
+            ("b1", "b"),
+            ("a2", "a"),
+            ("a3", "a"),
+            ("b2", "b"),
+        ]
+        .map(|(id, group)| SqsMessage {
+            message_id: Some(id.to_string()),
+            attributes: HashMap::from([(MESSAGE_GROUP_ATTRIBUTE.to_string(), group.to_string())]),
+            ..Default::default()
+        });
+        let processed = Mutex::new(vec![]);
+        let response = worker
+            .run(records.to_vec(), |record| {
+                let id = record.message_id.unwrap_or_default();
+                processed.lock().unwrap().push(id.clone());
+                async move {
+                    Ok(match id.as_str() {
+                        "a2" => Outcome::Retry("provider error".to_string()),
+                        // without a dead letter queue the redrive policy has to move it
+                        "b2" => Outcome::DeadLetter("no body".to_string()),
+                        _ => Outcome::Processed,
+                    })
+                }
+            })
+            .await;
+        let mut failed = response
+            .batch_item_failures
+            .into_iter()
+            .map(|failure| failure.item_identifier)
+            .collect::<Vec<_>>();
+        failed.sort();
+        assert_eq!(failed, ["a2", "a3", "b2"]);
+        let mut processed = processed.into_inner().unwrap();
+        processed.sort();
+        assert_eq!(processed, ["a1", "a2", "b1", "b2"]);
+    }
+}
@@ -0,0 +1,138 @@
+use anyhow::Result;
+use aws_lambda_events::event::sqs::SqsMessage;
+use mongoose::{bson::doc, Model};
+
+use super::{receive_count, Outcome};
+use crate::{
+    aws::s3::Client,
+    eleven_labs::{ElevenLabs, ProviderError},
+    models::{
+        audit_event::{AuditAction, AuditEvent},
+        consent::Consent,
+        insert_once,
+        provider_voice::ProviderVoice,
+        usage_event::{UsageEvent, UsageKind},
+        voice::{Voice, VoiceStatus},
+    },
+    types::{Envelope, TrainSampleFifoMessage},
+};
+
+pub const NAME: &str = "train-sample";
+
+pub async fn process(
+    message: SqsMessage,
+    eleven_labs: &ElevenLabs,
+    sample_bucket: &Client,
+) -> Result<Outcome> {
+    // the messages go back to the queue until the provider recovers
+    if let Some(open_for) = eleven_labs.circuit_open() {
+        let cooldown = eleven_labs.breaker.cooldown;
+        return Ok(Outcome::circuit_open(
+            cooldown,
+            open_for,
+            receive_count(&message),
+        ));
+    }
+    let Some(body) = message.body else {
+        return Ok(Outcome::DeadLetter("message has no body".to_string()));
+    };
+    let envelope = Envelope::<TrainSampleFifoMessage>::open(&body)?;
+    tracing::info!(
+        "[{NAME}] {} correlation {} attempt {} enqueued by {} at {}",
+        envelope.payload.voice_id,
+        envelope.correlation_id,
+        envelope.attempt,
+        envelope.actor,
+        envelope.enqueued_at
+    );
+    let data = &envelope.payload;
+    let voice = Voice::read_by_id(&data.voice_id).await?;
+    if voice.status == VoiceStatus::Active {
+        return Ok(Outcome::Skipped(format!(
+            "voice {} is already active",
+            voice.id
+        )));
+    }
+    if !Consent::is_verified_for(&voice.id).await {
+        AuditEvent::record_once(
+            format!("voice-returned-to-draft:{}", envelope.correlation_id),
+            &voice.workspace,
+            &format!("worker:{NAME}"),
+            AuditAction::VoiceReturnedToDraft,
+            format!("voice:{}", voice.id),
+            envelope.request_id.as_deref(),
+        )
+        .await?;
+        Voice::update(
+            doc! { "_id": &voice.id },
+            doc! { "status": VoiceStatus::Draft.to_string() },
+        )
+        .await?;
+        return Ok(Outcome::Skipped(format!(
+            "voice {} has no verified consent",
+            voice.id
+        )));
+    }
+    // get sample from s3
+    let sample = sample_bucket.get_object(voice.sample_key()).await?;
+    let data = sample.body.collect().await?.to_vec();
+    // clone voice from eleven labs
+    let cloned_voice = match eleven_labs
+        .add_voice(&voice.name, &data, voice.description.as_deref())
+        .await
+    {
+        Ok(cloned_voice) => cloned_voice,
+        // the sample itself was rejected, so the voice can never train as is
+        Err(err @ ProviderError::Validation { .. }) => {
+            AuditEvent::record_once(
+                format!("voice-training-failed:{}", envelope.correlation_id),
+                &voice.workspace,
+                &format!("worker:{NAME}"),
+                AuditAction::VoiceTrainingFailed,
+                format!("voice:{}", voice.id),
+                envelope.request_id.as_deref(),
+            )
+            .await?;
+            Voice::update(
+                doc! { "_id": &voice.id },
+                doc! { "status": VoiceStatus::Failed.to_string() },
+            )
+            .await?;
+            return Ok(Outcome::DeadLetter(err.to_string()));
+        }
+        Err(err) => return Err(err.into()),
+    };
+    // recorded before linking, so reconciliation knows parrot owns the clone either way
+    ProviderVoice::record_clone(&cloned_voice.voice_id, &voice.workspace, &voice.id).await?;
+    // recorded before the status changes, a retry after a failed update skips neither
+    let usage = UsageEvent {
+        id: format!("clone:{}", cloned_voice.voice_id),
+        workspace: voice.workspace.to_string(),
+        kind: UsageKind::Clone,
+        voice: voice.id.to_string(),
+        actor: voice.actor.to_string(),
+        ..Default::default()
+    };
+    insert_once(&usage).await?;
+    AuditEvent::record_once(
+        format!("voice-trained:{}", cloned_voice.voice_id),
+        &voice.workspace,
+        &format!("worker:{NAME}"),
+        AuditAction::VoiceTrained,
+        format!("voice:{}", voice.id),
+        envelope.request_id.as_deref(),
+    )
+    .await?;
+    // update voice status
+    let updated_voice = Voice::update(
+        doc! { "_id": voice.id },
+        doc! {
+            "status": VoiceStatus::Active.to_string(),
+            "eleven_labs_id": Some(cloned_voice.voice_id),
+        },
+    )
+    .await?;
+    tracing::info!("VOICE {:?}", updated_voice);
+    tracing::info!("USAGE: {:?}", usage);
+    Ok(Outcome::Processed)
+}
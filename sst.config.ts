@@ -1,15 +1,41 @@
 import { type SSTConfig } from 'sst'
-import { Bucket, Function, Queue, type StackContext } from 'sst/constructs'
+import { Bucket, Cron, Function, Queue, type StackContext } from 'sst/constructs'
+
+// the workers refund work on the last receive, so they are told the same count
+const MAX_RECEIVE_COUNT = 5
 
 function ApiStack({ stack }: StackContext) {
-	const createOutputQueue = new Queue(stack, 'create-output-fifo', {
-		consumer: 'src/bin/handlers/queues/create-output.rs',
+	// permanent failures are forwarded here by the workers, anything else lands here after max receives
+	const createOutputDeadLetterQueue = new Queue(stack, 'create-output-dlq-fifo', {
 		cdk: { queue: { fifo: true } }
 	})
-	const trainVoiceQueue = new Queue(stack, 'train-sample-fifo', {
-		consumer: 'src/bin/handlers/queues/train-sample.rs',
+	const trainVoiceDeadLetterQueue = new Queue(stack, 'train-sample-dlq-fifo', {
 		cdk: { queue: { fifo: true } }
 	})
+	const createOutputQueue = new Queue(stack, 'create-output-fifo', {
+		consumer: {
+			function: 'src/bin/handlers/queues/create-output.rs',
+			cdk: { eventSource: { reportBatchItemFailures: true } }
+		},
+		cdk: {
+			queue: {
+				fifo: true,
+				deadLetterQueue: { queue: createOutputDeadLetterQueue.cdk.queue, maxReceiveCount: MAX_RECEIVE_COUNT }
+			}
+		}
+	})
+	const trainVoiceQueue = new Queue(stack, 'train-sample-fifo', {
+		consumer: {
+			function: 'src/bin/handlers/queues/train-sample.rs',
+			cdk: { eventSource: { reportBatchItemFailures: true } }
+		},
+		cdk: {
+			queue: {
+				fifo: true,
+				deadLetterQueue: { queue: trainVoiceDeadLetterQueue.cdk.queue, maxReceiveCount: MAX_RECEIVE_COUNT }
+			}
+		}
+	})
 	const api = new Function(stack, 'api', {
 		handler: 'src/bin/handlers/api.rs',
 		url: { cors: true }
@@ -34,12 +60,35 @@ function ApiStack({ stack }: StackContext) {
 		}
 	})
 
+	new Cron(stack, 'purge-voices', {
+		schedule: 'rate(1 day)',
+		job: {
+			function: {
+				handler: 'src/bin/handlers/crons/purge-voices.rs',
+				timeout: 300,
+			}
+		}
+	})
+
+	new Cron(stack, 'reconcile-voices', {
+		schedule: 'rate(6 hours)',
+		job: {
+			function: {
+				handler: 'src/bin/handlers/crons/reconcile-voices.rs',
+				timeout: 300,
+			}
+		}
+	})
+
 	const functions = stack.getAllFunctions()
 	functions.forEach((fn) => {
 		fn.addEnvironment('CREATE_OUTPUT_QUEUE_URL', createOutputQueue.cdk.queue.queueUrl)
 		fn.addEnvironment('TRAIN_VOICE_QUEUE_URL', trainVoiceQueue.cdk.queue.queueUrl)
+		fn.addEnvironment('CREATE_OUTPUT_DEAD_LETTER_QUEUE_URL', createOutputDeadLetterQueue.cdk.queue.queueUrl)
+		fn.addEnvironment('TRAIN_VOICE_DEAD_LETTER_QUEUE_URL', trainVoiceDeadLetterQueue.cdk.queue.queueUrl)
 		fn.addEnvironment('SAMPLES_BUCKET_NAME', sampleBucket.bucketName)
 		fn.addEnvironment('OUTPUTS_BUCKET_NAME', outputBucket.bucketName)
+		fn.addEnvironment('WORKER_MAX_RECEIVE_COUNT', String(MAX_RECEIVE_COUNT))
 		fn.attachPermissions(['s3', 'sqs'])
 	})
 }
@@ -62,6 +111,13 @@ export default {
 				MONGO_URI: process.env.MONGO_URI,
 				AUTHENTICATION_TOKEN: process.env.AUTHENTICATION_TOKEN,
 				ELEVEN_LABS_API_KEY: process.env.ELEVEN_LABS_API_KEY,
+				VOICE_RETENTION_DAYS: process.env.VOICE_RETENTION_DAYS,
+				PURGE_VOICE_OUTPUTS: process.env.PURGE_VOICE_OUTPUTS,
+				RECONCILE_REPAIR: process.env.RECONCILE_REPAIR,
+				RECONCILE_GRACE_MINS: process.env.RECONCILE_GRACE_MINS,
+				JWKS_URL: process.env.JWKS_URL,
+				JWT_ISSUER: process.env.JWT_ISSUER,
+				JWT_AUDIENCE: process.env.JWT_AUDIENCE,
 			}
 		})
 		app.stack(ApiStack)